@@ -0,0 +1,15 @@
+use std::process::Command;
+
+fn main() {
+    // embed the short commit hash so `!otcbot version` can report the
+    // exact build; falls back to "unknown" outside a git checkout
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=OTCBOT_GIT_SHA={sha}");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
@@ -0,0 +1,937 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Bot configuration as read from `config.yaml`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub matrix: Matrix,
+    pub registry: Registry,
+    /// Prefix that addresses the bot in a room. Defaults to `!otcbot`.
+    pub command_prefix: Option<String>,
+    /// Additional prefixes that address the bot, e.g. a short alias
+    /// like `!otc` or the bot's display name.
+    #[serde(default)]
+    pub command_prefixes: Vec<String>,
+    /// Commands a single user may run per minute. Must be at least 1.
+    /// Defaults to 10.
+    pub command_rate_limit: Option<u32>,
+    /// Seconds a room must wait between `party` commands. Defaults
+    /// to 30.
+    pub party_cooldown_secs: Option<u64>,
+    /// Ignore commands older than this many seconds, so a backlog
+    /// replayed after downtime cannot trigger surprise imports.
+    /// Defaults to 300.
+    pub max_command_age_secs: Option<u64>,
+    /// Address to serve Prometheus metrics on, e.g. `0.0.0.0:9090`.
+    /// Metrics are disabled when unset.
+    pub metrics_addr: Option<String>,
+    /// File to append one JSON line per import to, for auditing.
+    /// Disabled when unset.
+    pub audit_log_path: Option<String>,
+    /// Mirror a fixed set of image/tag pairs on a schedule, independent
+    /// of chat commands. Disabled when unset.
+    pub schedule: Option<Schedule>,
+    /// HTTP endpoint for triggering imports from CI. Disabled when
+    /// unset.
+    pub webhook: Option<Webhook>,
+    /// Templates for the bot's casual replies. Built-in replies are
+    /// used when unset.
+    pub greetings: Option<Greetings>,
+    /// Subcommands allowed per room ID. Rooms without an entry fall
+    /// back to `default_room_commands`.
+    #[serde(default)]
+    pub room_permissions: HashMap<String, Vec<String>>,
+    /// Subcommands allowed in rooms that have no `room_permissions`
+    /// entry. All commands are allowed when unset.
+    pub default_room_commands: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Return the configured command prefix, falling back to `!otcbot`.
+    /// This is the primary prefix shown in help output.
+    pub fn command_prefix(&self) -> &str {
+        self.command_prefix.as_deref().unwrap_or("!otcbot")
+    }
+
+    /// All prefixes that address the bot: the primary prefix plus any
+    /// `command_prefixes` aliases, without duplicates.
+    pub fn command_prefixes(&self) -> Vec<&str> {
+        let mut prefixes = vec![self.command_prefix()];
+        for prefix in &self.command_prefixes {
+            if !prefixes.contains(&prefix.as_str()) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+
+    /// Return the per-user command rate limit, falling back to 10.
+    pub fn command_rate_limit(&self) -> u32 {
+        self.command_rate_limit.unwrap_or(10)
+    }
+
+    /// Return the stale-command cutoff, falling back to 300 seconds.
+    pub fn max_command_age_secs(&self) -> u64 {
+        self.max_command_age_secs.unwrap_or(300)
+    }
+
+    /// Return the per-room party cooldown, falling back to 30 seconds.
+    pub fn party_cooldown_secs(&self) -> u64 {
+        self.party_cooldown_secs.unwrap_or(30)
+    }
+
+    /// Whether `command` may be used in `room_id`, honoring the
+    /// per-room permissions and the default policy.
+    pub fn command_allowed(&self, room_id: &str, command: &str) -> bool {
+        let allowed = match self.room_permissions.get(room_id) {
+            Some(allowed) => allowed,
+            None => match &self.default_room_commands {
+                Some(allowed) => allowed,
+                None => return true,
+            },
+        };
+        allowed.iter().any(|name| name == command)
+    }
+}
+
+/// Templates for the bot's casual replies.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Greetings {
+    /// Reply to "gm". Supports `{name}` (the sender's display name,
+    /// falling back to the MXID) and `{mxid}` placeholders.
+    pub gm: Option<String>,
+}
+
+/// Images mirrored automatically on a fixed cadence.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Schedule {
+    /// Minutes between sync runs.
+    pub interval_minutes: u64,
+    /// The image/tag pairs to keep in sync.
+    pub entries: Vec<ScheduleEntry>,
+}
+
+/// One image/tag pair of the sync schedule.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScheduleEntry {
+    /// Key into `registry.images`.
+    pub image: String,
+    pub tag: String,
+}
+
+/// Settings for the import webhook, a minimal HTTP endpoint that lets
+/// CI pipelines trigger the same imports as the chat command.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Webhook {
+    /// Address to listen on, e.g. `127.0.0.1:8100`.
+    pub addr: String,
+    /// Shared secret callers must present as `Authorization: Bearer
+    /// <token>`.
+    pub token: String,
+}
+
+/// Which room invitations the bot accepts.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutojoinPolicy {
+    /// Join any room the bot is invited to.
+    All,
+    /// Join only when the inviter is listed in `matrix.admins`.
+    AdminsOnly,
+    /// Never join from invites; only `matrix.rooms` is joined.
+    None,
+}
+
+/// Matrix connection settings.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Matrix {
+    pub homeserver: String,
+    pub username: String,
+    /// Password for interactive login. Either this or `access_token`
+    /// must be set.
+    pub password: Option<String>,
+    /// Command whose trimmed stdout becomes the password, for secret
+    /// managers like `pass` or vault-agent. Takes precedence over
+    /// `password` and the environment override.
+    pub password_command: Option<String>,
+    /// Access token to restore an existing session instead of logging
+    /// in with a password. Requires `username` to be a full MXID.
+    pub access_token: Option<String>,
+    /// Device ID the access token belongs to. Defaults to `OTCBOT`.
+    pub device_id: Option<String>,
+    /// Display name of the bot's device in the account's device list,
+    /// to tell multiple instances apart. Defaults to `otcbot`.
+    pub device_display_name: Option<String>,
+    /// PEM file with the TLS client certificate to present to the
+    /// homeserver, for mTLS reverse proxies. Requires
+    /// `client_key_path`.
+    pub client_cert_path: Option<String>,
+    /// PEM file with the private key belonging to `client_cert_path`.
+    pub client_key_path: Option<String>,
+    /// Maximum number of login attempts before giving up. Defaults to 5.
+    pub max_login_retries: Option<u32>,
+    /// Base delay in seconds between room join retries, doubled on
+    /// every attempt. Defaults to 2.
+    pub autojoin_base_delay_secs: Option<u64>,
+    /// Give up joining a room once the backoff delay exceeds this many
+    /// seconds. Defaults to 3600.
+    pub autojoin_max_delay_secs: Option<u64>,
+    /// Which invites to accept. Defaults to `all`.
+    pub autojoin_policy: Option<AutojoinPolicy>,
+    /// Room ID to post operational notifications to, e.g. on startup.
+    pub notify_room: Option<String>,
+    /// Room IDs or aliases to join proactively on startup, in addition
+    /// to rooms the bot is invited to.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// MXIDs allowed to run privileged commands.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Send read receipts for handled messages. Defaults to true.
+    pub send_read_receipts: Option<bool>,
+    /// Send typing notices while commands run. Defaults to true.
+    pub send_typing_notices: Option<bool>,
+    /// React to commands with 👀 while they run and ✅/❌ when they
+    /// finish. Defaults to false.
+    pub use_reactions: Option<bool>,
+    /// Prefix result messages with ✅/❌ so outcomes are scannable.
+    /// Defaults to true; turn off for rooms or clients where emoji are
+    /// undesirable.
+    pub use_emoji: Option<bool>,
+    /// Send command responses as threaded replies to the triggering
+    /// message, keeping import logs grouped under the command.
+    #[serde(default)]
+    pub reply_in_thread: bool,
+    /// Sync with a lazy-loading member filter so only the members
+    /// needed to render a room are fetched. Cuts startup time and
+    /// memory on accounts in many or large rooms.
+    #[serde(default)]
+    pub lazy_load_members: bool,
+    /// Enable end-to-end encryption support. The bot then decrypts
+    /// incoming messages and sends encrypted replies in E2EE rooms. It
+    /// sends to all devices of a user, verified or not; verify the bot's
+    /// device from an admin account if your rooms require it.
+    #[serde(default)]
+    pub encryption: bool,
+}
+
+impl Matrix {
+    /// Return the login retry cap, falling back to 5.
+    pub fn max_login_retries(&self) -> u32 {
+        self.max_login_retries.unwrap_or(5)
+    }
+
+    /// Return the device display name, falling back to `otcbot`.
+    pub fn device_display_name(&self) -> &str {
+        self.device_display_name.as_deref().unwrap_or("otcbot")
+    }
+
+    /// Return the initial join retry delay, falling back to 2 seconds.
+    pub fn autojoin_base_delay_secs(&self) -> u64 {
+        self.autojoin_base_delay_secs.unwrap_or(2)
+    }
+
+    /// Return the join retry delay cap, falling back to 3600 seconds.
+    pub fn autojoin_max_delay_secs(&self) -> u64 {
+        self.autojoin_max_delay_secs.unwrap_or(3600)
+    }
+
+    /// Return the invite policy, falling back to accepting all invites.
+    pub fn autojoin_policy(&self) -> AutojoinPolicy {
+        self.autojoin_policy.unwrap_or(AutojoinPolicy::All)
+    }
+
+    /// Whether to send read receipts, falling back to true.
+    pub fn send_read_receipts(&self) -> bool {
+        self.send_read_receipts.unwrap_or(true)
+    }
+
+    /// Whether to send typing notices, falling back to true.
+    pub fn send_typing_notices(&self) -> bool {
+        self.send_typing_notices.unwrap_or(true)
+    }
+
+    /// Whether to react to commands with emoji, falling back to false.
+    pub fn use_reactions(&self) -> bool {
+        self.use_reactions.unwrap_or(false)
+    }
+
+    /// Whether to prefix result messages with ✅/❌, falling back to
+    /// true.
+    pub fn use_emoji(&self) -> bool {
+        self.use_emoji.unwrap_or(true)
+    }
+
+    /// Whether the given MXID may run privileged commands.
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.admins.iter().any(|admin| admin == user_id)
+    }
+}
+
+/// Container registry mirroring settings.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Registry {
+    /// Credentials for the downstream mirror registries, sent as
+    /// `--dest-creds` on copies and `--creds` on mirror-side calls.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Command whose trimmed stdout becomes the password, for secret
+    /// managers like `pass` or vault-agent. Takes precedence over
+    /// `password` and the environment override.
+    pub password_command: Option<String>,
+    /// Credentials for the upstream registries, for mirroring from
+    /// sources that require authentication. When unset upstreams are
+    /// contacted anonymously.
+    pub src_username: Option<String>,
+    pub src_password: Option<String>,
+    /// Command whose trimmed stdout becomes the upstream password.
+    /// Takes precedence over `src_password` and the environment
+    /// override.
+    pub src_password_command: Option<String>,
+    /// Path to the skopeo binary. When unset the bot relies on `$PATH`.
+    pub skopeo_path: Option<String>,
+    /// Wrapper command (as argv words, so no shell quoting applies) to
+    /// run skopeo through, e.g.
+    /// `["podman", "run", "--rm", "quay.io/skopeo/stable"]` on hosts
+    /// without a native skopeo. When unset the binary runs directly.
+    pub skopeo_runner: Option<Vec<String>>,
+    /// Timeout for skopeo invocations in seconds. Defaults to 600.
+    pub skopeo_timeout_secs: Option<u64>,
+    /// Maximum number of imports running at once across all rooms and
+    /// users. Defaults to 2. Applied at startup; a reload does not
+    /// resize the running limit.
+    pub max_concurrent_imports: Option<usize>,
+    /// Queue imports when the limit is reached instead of rejecting
+    /// them. Defaults to true.
+    pub queue_imports: Option<bool>,
+    /// Maximum number of skopeo output lines echoed into the room
+    /// before the oldest are dropped. Defaults to 40.
+    pub max_log_lines: Option<usize>,
+    /// Upload the full log as a text attachment instead of echoing it
+    /// inline once it exceeds this many bytes. Disabled when unset.
+    pub log_attachment_threshold: Option<usize>,
+    /// Most tags a single `prune` may delete without `--force`.
+    /// Defaults to 10.
+    pub prune_max_tags: Option<usize>,
+    /// Regex patterns whose matches are replaced with `***` in skopeo
+    /// output before it is posted to a room, for registries that embed
+    /// tokens in URLs or headers. Invalid patterns are skipped with a
+    /// warning at use.
+    pub redact_patterns: Option<Vec<String>>,
+    /// Verify the upstream registry's TLS certificate
+    /// (`--src-tls-verify`). Unset leaves skopeo's default, which
+    /// verifies. Per-image settings take precedence.
+    pub src_tls_verify: Option<bool>,
+    /// Verify the downstream registry's TLS certificate
+    /// (`--dest-tls-verify`). Unset leaves skopeo's default, which
+    /// verifies. Per-image settings take precedence.
+    pub dest_tls_verify: Option<bool>,
+    /// JSON file mapping image:tag to the upstream digest of the last
+    /// successful import, so unchanged images are not copied again.
+    /// Disabled when unset.
+    pub digest_cache_path: Option<String>,
+    pub images: HashMap<String, ImageConfig>,
+    /// Sign downstream images with cosign after a successful import.
+    /// Disabled when unset.
+    pub sign: Option<SignConfig>,
+}
+
+/// Settings for signing imported images with cosign.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SignConfig {
+    /// Path to the cosign private key.
+    pub key_path: String,
+    /// Path to the cosign binary. When unset the bot relies on `$PATH`.
+    pub cosign_path: Option<String>,
+    /// Password of the private key, passed to cosign via
+    /// `COSIGN_PASSWORD`.
+    pub password: Option<String>,
+}
+
+impl SignConfig {
+    /// Return the configured cosign binary, falling back to `cosign` on
+    /// `$PATH`.
+    pub fn cosign(&self) -> &str {
+        self.cosign_path.as_deref().unwrap_or("cosign")
+    }
+}
+
+impl Registry {
+    /// Return the configured skopeo binary, falling back to `skopeo` on
+    /// `$PATH`.
+    pub fn skopeo(&self) -> &str {
+        self.skopeo_path.as_deref().unwrap_or("skopeo")
+    }
+
+    /// How the skopeo invocation is rendered in room echoes: the runner
+    /// words plus the binary, or just the binary.
+    pub fn skopeo_display(&self) -> String {
+        match &self.skopeo_runner {
+            Some(runner) if !runner.is_empty() => {
+                format!("{} {}", runner.join(" "), self.skopeo())
+            }
+            _ => self.skopeo().to_string(),
+        }
+    }
+
+    /// Return the skopeo timeout in seconds, falling back to 600.
+    pub fn skopeo_timeout_secs(&self) -> u64 {
+        self.skopeo_timeout_secs.unwrap_or(600)
+    }
+
+    /// Return the global import concurrency limit, falling back to 2.
+    pub fn max_concurrent_imports(&self) -> usize {
+        self.max_concurrent_imports.unwrap_or(2)
+    }
+
+    /// Whether to queue imports over the limit, falling back to true.
+    pub fn queue_imports(&self) -> bool {
+        self.queue_imports.unwrap_or(true)
+    }
+
+    /// Return the prune deletion cap, falling back to 10.
+    pub fn prune_max_tags(&self) -> usize {
+        self.prune_max_tags.unwrap_or(10)
+    }
+
+    /// Redaction patterns for room-posted output, empty when unset.
+    pub fn redact_patterns(&self) -> &[String] {
+        self.redact_patterns.as_deref().unwrap_or(&[])
+    }
+
+    /// Return the room log line cap, falling back to 40.
+    pub fn max_log_lines(&self) -> usize {
+        self.max_log_lines.unwrap_or(40)
+    }
+
+    /// Return `user:pass` credentials for the downstream mirror when
+    /// both are configured.
+    pub fn credentials(&self) -> Option<String> {
+        match (&self.username, &self.password) {
+            (Some(user), Some(pass)) => Some(format!("{user}:{pass}")),
+            _ => None,
+        }
+    }
+
+    /// Return `user:pass` credentials for the upstream side when both
+    /// are configured; None means upstreams are contacted anonymously.
+    pub fn src_credentials(&self) -> Option<String> {
+        match (&self.src_username, &self.src_password) {
+            (Some(user), Some(pass)) => Some(format!("{user}:{pass}")),
+            _ => None,
+        }
+    }
+
+    /// Look up an image by its primary key or one of its aliases,
+    /// returning the canonical key alongside the configuration.
+    pub fn resolve_image(&self, key: &str) -> Option<(&str, &ImageConfig)> {
+        if let Some((canonical, image)) = self.images.get_key_value(key) {
+            return Some((canonical.as_str(), image));
+        }
+        self.images
+            .iter()
+            .find(|(_, image)| {
+                image.aliases().iter().any(|alias| alias == key)
+            })
+            .map(|(canonical, image)| (canonical.as_str(), image))
+    }
+
+    /// Check every image entry for obvious mistakes that would otherwise
+    /// only surface once a skopeo call fails. Returns all problems found,
+    /// sorted for stable output.
+    pub fn validate(&self) -> Vec<String> {
+        fn check(
+            key: &str,
+            what: &str,
+            reference: &str,
+            problems: &mut Vec<String>,
+        ) {
+            if reference.is_empty() {
+                problems.push(format!("image {key}: empty {what}"));
+            } else if !reference.chars().all(|c| {
+                c.is_ascii_alphanumeric() || "./-_:".contains(c)
+            }) {
+                problems.push(format!(
+                    "image {key}: invalid characters in {what} {reference}"
+                ));
+            }
+        }
+
+        let mut problems = Vec::new();
+        let mut seen_aliases: HashMap<&str, &str> = HashMap::new();
+        for (key, image) in &self.images {
+            for alias in image.aliases() {
+                if self.images.contains_key(alias.as_str()) {
+                    problems.push(format!(
+                        "image {key}: alias {alias} collides with an \
+                         image key"
+                    ));
+                } else if let Some(other) =
+                    seen_aliases.insert(alias.as_str(), key)
+                {
+                    let mut pair = [other, key.as_str()];
+                    pair.sort();
+                    problems.push(format!(
+                        "alias {alias} declared by both {} and {}",
+                        pair[0], pair[1]
+                    ));
+                }
+            }
+            check(key, "upstream", &image.upstream, &mut problems);
+            for target in image.downstream.targets() {
+                check(key, "downstream", target, &mut problems);
+            }
+            for arg in image.extra_args() {
+                // anything not shaped like a flag would shift skopeo's
+                // subcommand or source/destination positionals
+                if !arg.starts_with('-') {
+                    problems.push(format!(
+                        "image {key}: extra_args entry {arg} is not a flag"
+                    ));
+                }
+            }
+        }
+        problems.sort();
+        problems
+    }
+}
+
+/// A single image known to the bot.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageConfig {
+    pub upstream: String,
+    pub downstream: Downstream,
+    /// Copy all architectures (`--all`). Defaults to true; set to false
+    /// for single-arch images to avoid skopeo warnings. An explicit
+    /// `--platform` on the import command takes precedence either way.
+    pub all_arch: Option<bool>,
+    /// Additional skopeo flags for this image's copies, e.g.
+    /// `--dest-tls-verify=false` or `--retry-times=3`. Flags only;
+    /// positional arguments are rejected at config load.
+    pub extra_args: Option<Vec<String>>,
+    /// Alternative names this image may be referred to by in commands.
+    /// Aliases must be unique across the whole config.
+    pub aliases: Option<Vec<String>>,
+    /// Tags that may be imported, as glob patterns (e.g. `1.*`,
+    /// `stable`). Unset allows any tag.
+    pub allowed_tags: Option<Vec<String>>,
+    /// Per-image override of `registry.src_tls_verify`.
+    pub src_tls_verify: Option<bool>,
+    /// Per-image override of `registry.dest_tls_verify`.
+    pub dest_tls_verify: Option<bool>,
+}
+
+impl ImageConfig {
+    /// Whether to copy all architectures, falling back to true.
+    pub fn all_arch(&self) -> bool {
+        self.all_arch.unwrap_or(true)
+    }
+
+    /// Extra skopeo flags for this image, empty when unset.
+    pub fn extra_args(&self) -> &[String] {
+        self.extra_args.as_deref().unwrap_or(&[])
+    }
+
+    /// Alias keys for this image, empty when unset.
+    pub fn aliases(&self) -> &[String] {
+        self.aliases.as_deref().unwrap_or(&[])
+    }
+
+    /// Effective `--src-tls-verify` value: the per-image setting, then
+    /// the registry-wide one. None leaves skopeo's default.
+    pub fn src_tls_verify(&self, registry: &Registry) -> Option<bool> {
+        self.src_tls_verify.or(registry.src_tls_verify)
+    }
+
+    /// Effective `--dest-tls-verify` value: the per-image setting, then
+    /// the registry-wide one. None leaves skopeo's default.
+    pub fn dest_tls_verify(&self, registry: &Registry) -> Option<bool> {
+        self.dest_tls_verify.or(registry.dest_tls_verify)
+    }
+}
+
+/// One or several downstream references. A plain string keeps parsing
+/// so existing single-target configs stay valid.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Downstream {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Downstream {
+    /// All downstream references in configuration order.
+    pub fn targets(&self) -> Vec<&str> {
+        match self {
+            Downstream::One(target) => vec![target.as_str()],
+            Downstream::Many(targets) => {
+                targets.iter().map(String::as_str).collect()
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Load and merge several configuration files in order; later files
+    /// win. The merge happens on the YAML documents so an overlay can
+    /// stay partial: mappings (including `registry.images`) merge key
+    /// by key, scalars and lists are replaced outright.
+    pub fn from_config_files(paths: &[String]) -> anyhow::Result<Config> {
+        anyhow::ensure!(!paths.is_empty(), "No configuration file given");
+        let mut merged = serde_yaml::Value::Null;
+        for path in paths {
+            let f = File::open(path)
+                .with_context(|| format!("Could not open {path}"))?;
+            // serde_yaml reports line/column information in its Display
+            // output
+            let value: serde_yaml::Value = serde_yaml::from_reader(f)
+                .with_context(|| format!("Could not parse {path}"))?;
+            merged = merge_values(merged, value);
+        }
+        let mut config: Config =
+            serde_yaml::from_value(merged).with_context(|| {
+                format!(
+                    "Invalid merged configuration from {}",
+                    paths.join(", ")
+                )
+            })?;
+        config.apply_env_overrides();
+        config.resolve_password_commands()?;
+        let problems = config.registry.validate();
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "Invalid registry configuration:\n{}",
+                problems.join("\n")
+            );
+        }
+        // a limit of 0 would deny every command (and used to panic in
+        // the rate limiter), so it is a configuration mistake
+        if config.command_rate_limit() == 0 {
+            anyhow::bail!("command_rate_limit must be at least 1");
+        }
+        Ok(config)
+    }
+
+    /// Fetch secrets from any configured `password_command`s, replacing
+    /// the literal password fields. Running last, they take precedence
+    /// over both the file values and the environment overrides.
+    fn resolve_password_commands(&mut self) -> anyhow::Result<()> {
+        if let Some(command) = &self.matrix.password_command {
+            self.matrix.password = Some(run_secret_command(command)?);
+        }
+        if let Some(command) = &self.registry.password_command {
+            self.registry.password = Some(run_secret_command(command)?);
+        }
+        if let Some(command) = &self.registry.src_password_command {
+            self.registry.src_password = Some(run_secret_command(command)?);
+        }
+        Ok(())
+    }
+
+    /// Overlay secrets from the environment over the file values so they
+    /// do not have to live in the YAML file. Environment variables take
+    /// precedence.
+    fn apply_env_overrides(&mut self) {
+        if let Ok(password) = std::env::var("OTCBOT_MATRIX_PASSWORD") {
+            self.matrix.password = Some(password);
+        }
+        if let Ok(token) = std::env::var("OTCBOT_MATRIX_ACCESS_TOKEN") {
+            self.matrix.access_token = Some(token);
+        }
+        if let Ok(username) = std::env::var("OTCBOT_REGISTRY_USERNAME") {
+            self.registry.username = Some(username);
+        }
+        if let Ok(password) = std::env::var("OTCBOT_REGISTRY_PASSWORD") {
+            self.registry.password = Some(password);
+        }
+        if let Ok(username) = std::env::var("OTCBOT_REGISTRY_SRC_USERNAME") {
+            self.registry.src_username = Some(username);
+        }
+        if let Ok(password) = std::env::var("OTCBOT_REGISTRY_SRC_PASSWORD") {
+            self.registry.src_password = Some(password);
+        }
+    }
+}
+
+/// Recursively merge two YAML values: mappings merge key by key,
+/// everything else is replaced by the overlay.
+fn merge_values(
+    base: serde_yaml::Value,
+    overlay: serde_yaml::Value,
+) -> serde_yaml::Value {
+    match (base, overlay) {
+        (
+            serde_yaml::Value::Mapping(mut base),
+            serde_yaml::Value::Mapping(overlay),
+        ) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            serde_yaml::Value::Mapping(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+/// Run a `password_command` through the shell and return its trimmed
+/// stdout, so trailing newlines from tools like `pass` do not end up in
+/// the secret.
+fn run_secret_command(command: &str) -> anyhow::Result<String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .with_context(|| {
+            format!("Could not run password command {command}")
+        })?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Password command {command} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_config_file(path: &str) -> anyhow::Result<Config> {
+        Config::from_config_files(&[path.to_string()])
+    }
+
+    fn write_config(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let err =
+            from_config_file("/nonexistent/config.yaml").unwrap_err();
+        assert!(err.to_string().contains("Could not open"));
+    }
+
+    #[test]
+    fn invalid_yaml_is_an_error() {
+        let path = write_config("otcbot-invalid.yaml", "matrix: [unclosed");
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Could not parse"));
+    }
+
+    #[test]
+    fn env_overrides_yaml_values() {
+        let path = write_config(
+            "otcbot-env-override.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"file-secret\"\n\
+             registry:\n\
+             \x20 password: \"file-registry-secret\"\n\
+             \x20 images: {}\n",
+        );
+        std::env::set_var("OTCBOT_MATRIX_PASSWORD", "env-secret");
+        std::env::set_var("OTCBOT_REGISTRY_PASSWORD", "env-registry-secret");
+        let config =
+            from_config_file(path.to_str().unwrap()).unwrap();
+        std::env::remove_var("OTCBOT_MATRIX_PASSWORD");
+        std::env::remove_var("OTCBOT_REGISTRY_PASSWORD");
+        assert_eq!(config.matrix.password.as_deref(), Some("env-secret"));
+        assert_eq!(
+            config.registry.password.as_deref(),
+            Some("env-registry-secret")
+        );
+    }
+
+    #[test]
+    fn password_command_overrides_literal_password() {
+        let path = write_config(
+            "otcbot-password-command.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"file-secret\"\n\
+             \x20 password_command: \"echo command-secret\"\n\
+             registry:\n\
+             \x20 images: {}\n",
+        );
+        let config =
+            from_config_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            config.matrix.password.as_deref(),
+            Some("command-secret")
+        );
+    }
+
+    #[test]
+    fn failing_password_command_is_an_error() {
+        let path = write_config(
+            "otcbot-password-command-fail.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password_command: \"false\"\n\
+             registry:\n\
+             \x20 images: {}\n",
+        );
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        assert!(format!("{err:#}").contains("Password command"));
+    }
+
+    #[test]
+    fn invalid_image_entries_are_rejected() {
+        let path = write_config(
+            "otcbot-bad-images.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"secret\"\n\
+             registry:\n\
+             \x20 images:\n\
+             \x20   bad:\n\
+             \x20     upstream: \"\"\n\
+             \x20     downstream: \"registry example com/mirror/bad\"\n",
+        );
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("empty upstream"));
+        assert!(message.contains("invalid characters in downstream"));
+    }
+
+    #[test]
+    fn overlay_files_merge_images_and_override_scalars() {
+        let base = write_config(
+            "otcbot-merge-base.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"secret\"\n\
+             command_rate_limit: 5\n\
+             registry:\n\
+             \x20 images:\n\
+             \x20   nginx:\n\
+             \x20     upstream: \"docker.io/library/nginx\"\n\
+             \x20     downstream: \"registry.example.com/mirror/nginx\"\n",
+        );
+        let overlay = write_config(
+            "otcbot-merge-overlay.yaml",
+            "command_rate_limit: 20\n\
+             registry:\n\
+             \x20 images:\n\
+             \x20   redis:\n\
+             \x20     upstream: \"docker.io/library/redis\"\n\
+             \x20     downstream: \"registry.example.com/mirror/redis\"\n",
+        );
+        let config = Config::from_config_files(&[
+            base.to_str().unwrap().to_string(),
+            overlay.to_str().unwrap().to_string(),
+        ])
+        .unwrap();
+        assert_eq!(config.command_rate_limit(), 20);
+        assert!(config.registry.images.contains_key("nginx"));
+        assert!(config.registry.images.contains_key("redis"));
+        assert_eq!(config.matrix.username, "otcbot");
+    }
+
+    #[test]
+    fn zero_command_rate_limit_is_rejected() {
+        let path = write_config(
+            "otcbot-zero-rate-limit.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"secret\"\n\
+             command_rate_limit: 0\n\
+             registry:\n\
+             \x20 images: {}\n",
+        );
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        assert!(format!("{err:#}")
+            .contains("command_rate_limit must be at least 1"));
+    }
+
+    #[test]
+    fn duplicate_aliases_are_rejected() {
+        let path = write_config(
+            "otcbot-dup-alias.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"secret\"\n\
+             registry:\n\
+             \x20 images:\n\
+             \x20   nginx:\n\
+             \x20     upstream: \"docker.io/library/nginx\"\n\
+             \x20     downstream: \"registry.example.com/mirror/nginx\"\n\
+             \x20     aliases: [web]\n\
+             \x20   httpd:\n\
+             \x20     upstream: \"docker.io/library/httpd\"\n\
+             \x20     downstream: \"registry.example.com/mirror/httpd\"\n\
+             \x20     aliases: [web]\n",
+        );
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        assert!(format!("{err:#}")
+            .contains("alias web declared by both httpd and nginx"));
+    }
+
+    #[test]
+    fn room_permissions_fall_back_to_default_policy() {
+        let path = write_config(
+            "otcbot-room-permissions.yaml",
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 password: \"secret\"\n\
+             registry:\n\
+             \x20 images: {}\n\
+             room_permissions:\n\
+             \x20 \"!general:example.com\": [party, ping]\n\
+             default_room_commands: [party, ping, status, registry]\n",
+        );
+        let config =
+            from_config_file(path.to_str().unwrap()).unwrap();
+        assert!(config.command_allowed("!general:example.com", "party"));
+        assert!(!config.command_allowed("!general:example.com", "registry"));
+        assert!(config.command_allowed("!ops:example.com", "registry"));
+        assert!(!config.command_allowed("!ops:example.com", "leave"));
+    }
+
+    #[test]
+    fn missing_matrix_section_is_an_error() {
+        let path = write_config(
+            "otcbot-no-matrix.yaml",
+            "registry:\n  images: {}\n",
+        );
+        let err =
+            from_config_file(path.to_str().unwrap()).unwrap_err();
+        assert!(format!("{err:#}").contains("matrix"));
+    }
+}
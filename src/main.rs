@@ -0,0 +1,4640 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod config;
+mod metrics;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context as _;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    config::SyncSettings,
+    encryption::{BackupDownloadStrategy, EncryptionSettings},
+    event_handler::Ctx,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    room::Room,
+    ruma::events::reaction::ReactionEventContent,
+    ruma::events::relation::Annotation,
+    ruma::events::room::member::StrippedRoomMemberEvent,
+    ruma::api::client::filter::FilterDefinition,
+    ruma::api::client::receipt::create_receipt::v3::ReceiptType,
+    ruma::events::receipt::ReceiptThread,
+    ruma::events::relation::Thread,
+    ruma::events::room::message::{
+        MessageType, OriginalSyncRoomMessageEvent, Relation,
+        ReplacementMetadata, RoomMessageEventContent,
+    },
+    ruma::{OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, RoomOrAliasId, UserId},
+    Client, LoopCtrl, RoomState, SessionMeta,
+};
+use tokio::io::{
+    AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpListener;
+use tokio::process::Command as ProcessCommand;
+use regex::Regex;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::AbortHandle;
+use tokio::time::sleep;
+use tracing::Instrument;
+
+use crate::config::{
+    AutojoinPolicy, Config, ImageConfig, Registry, SignConfig,
+};
+use crate::metrics::Metrics;
+
+/// Configuration shared with the event handlers; swapped atomically on
+/// reload so changes apply to subsequent commands without reconnecting.
+type SharedConfig = Arc<RwLock<Config>>;
+
+/// Runtime state shared with the event handlers.
+#[derive(Clone)]
+struct BotState {
+    started: Instant,
+    last_sync: Arc<Mutex<Option<SystemTime>>>,
+    /// Timestamps of recent commands per user, for rate limiting.
+    command_times: Arc<Mutex<HashMap<OwnedUserId, Vec<Instant>>>>,
+    /// When each room last partied, for the party cooldown.
+    party_times: Arc<Mutex<HashMap<OwnedRoomId, Instant>>>,
+    /// `image:tag` keys of imports currently running.
+    in_flight: Arc<Mutex<HashSet<String>>>,
+    /// Caps how many skopeo copies run at once across all rooms; sized
+    /// from `registry.max_concurrent_imports` at startup.
+    import_slots: Arc<Semaphore>,
+    /// Import jobs that are queued or running, keyed by job ID.
+    jobs: Arc<Mutex<BTreeMap<u64, JobInfo>>>,
+    /// Source of monotonically increasing job IDs.
+    next_job_id: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+    /// Serializes audit log writes so concurrent imports cannot
+    /// interleave lines.
+    audit_lock: Arc<Mutex<()>>,
+    /// Paths the configuration was merged from, for chat-driven reloads.
+    config_paths: Vec<String>,
+    /// Handle to the live configuration, swapped on reload.
+    shared_config: SharedConfig,
+    /// Signalled by the `restart` command to tear down and re-establish
+    /// the Matrix session without exiting the process.
+    restart: Arc<Notify>,
+}
+
+impl BotState {
+    /// Record a command from `sender` unless they exceeded `limit`
+    /// commands in the last minute; returns the seconds to wait if so.
+    fn check_rate_limit(&self, sender: &UserId, limit: u32) -> Option<u64> {
+        let mut command_times = self.command_times.lock().unwrap();
+        let timestamps = command_times.entry(sender.to_owned()).or_default();
+        timestamps.retain(|t| t.elapsed() < Duration::from_secs(60));
+        if timestamps.len() >= limit as usize {
+            let oldest = timestamps.iter().min().unwrap();
+            Some(60u64.saturating_sub(oldest.elapsed().as_secs()))
+        } else {
+            timestamps.push(Instant::now());
+            None
+        }
+    }
+}
+
+/// Build the clap command tree for messages addressed to the bot.
+fn otcbot_cmd(prefix: &str) -> Command {
+    Command::new(prefix.to_string())
+        .about("OTC Bot")
+        .subcommand_required(true)
+        .subcommand(Command::new("party").about("Party hard"))
+        .subcommand(Command::new("ping").about("Measure round-trip latency"))
+        .subcommand(Command::new("status").about("Show bot status"))
+        .subcommand(Command::new("leave").about("Leave the current room"))
+        .subcommand(Command::new("version").about("Show bot version"))
+        .subcommand(
+            Command::new("stats")
+                .about("Show per-command usage counters since startup"),
+        )
+        .subcommand(
+            Command::new("echo")
+                .about("Echo the given text back verbatim (debugging)")
+                .arg(
+                    Arg::new("TEXT")
+                        .num_args(0..)
+                        .trailing_var_arg(true)
+                        .help("Text to echo"),
+                ),
+        )
+        .subcommand(
+            Command::new("restart")
+                .about(
+                    "Log in again and resume sync without restarting \
+                     the process",
+                ),
+        )
+        .subcommand(
+            Command::new("whoami").about("Show the bot's Matrix identity"),
+        )
+        .subcommand(
+            Command::new("registry")
+                .about("Container registry operations")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("import")
+                        .about("Import image into the downstream registry")
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(Arg::new("TAG").required(true).help(
+                            "Image tag, or a sha256:... digest \
+                             (requires --as)",
+                        ))
+                        .arg(
+                            Arg::new("platform")
+                                .long("platform")
+                                .value_name("OS/ARCH")
+                                .help(
+                                    "Copy only this platform instead of \
+                                     all architectures",
+                                ),
+                        )
+                        .arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Show the skopeo command without \
+                                     running it",
+                                ),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Copy even if the cached upstream \
+                                     digest is unchanged",
+                                ),
+                        )
+                        .arg(
+                            Arg::new("as")
+                                .long("as")
+                                .value_name("DEST_TAG")
+                                .help(
+                                    "Store the image downstream under \
+                                     this tag instead of TAG",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import-all")
+                        .about("Import every configured image at a tag")
+                        .arg(Arg::new("TAG").required(true).help("Image tag")),
+                )
+                .subcommand(
+                    Command::new("copy")
+                        .about(
+                            "Copy an arbitrary reference, bypassing the \
+                             configured image map",
+                        )
+                        .arg(
+                            Arg::new("SRC_REF")
+                                .required(true)
+                                .help("Source, e.g. docker.io/library/nginx:1.25"),
+                        )
+                        .arg(
+                            Arg::new("DST_REF")
+                                .required(true)
+                                .help("Destination reference"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("mirror-from-file")
+                        .about(
+                            "Import a pasted list of `image tag` \
+                             lines, e.g. a release manifest",
+                        )
+                        .arg(
+                            Arg::new("LINES")
+                                .num_args(1..)
+                                .trailing_var_arg(true)
+                                .required(true)
+                                .help(
+                                    "image/tag pairs, one per line \
+                                     after the command",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    Command::new("list").about("List configured images"),
+                )
+                .subcommand(
+                    Command::new("show")
+                        .about("Show the configuration of one image")
+                        .arg(
+                            Arg::new("IMAGE").required(true).help("Image key"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("reload")
+                        .about("Reload the configuration file"),
+                )
+                .subcommand(
+                    Command::new("jobs")
+                        .about("List queued and running imports"),
+                )
+                .subcommand(
+                    Command::new("cancel")
+                        .about("Cancel a queued or running import job")
+                        .arg(
+                            Arg::new("JOB_ID")
+                                .required(true)
+                                .help("Job ID, e.g. 3"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("history")
+                        .about("Show recent imports from the audit log")
+                        .arg(
+                            Arg::new("IMAGE")
+                                .required(false)
+                                .help("Only show imports of this image key"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("inspect")
+                        .about("Show metadata of an upstream image")
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(Arg::new("TAG").required(true).help("Image tag")),
+                )
+                .subcommand(
+                    Command::new("diff")
+                        .about(
+                            "Compare upstream and downstream digests of \
+                             a tag",
+                        )
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(Arg::new("TAG").required(true).help("Image tag")),
+                )
+                .subcommand(
+                    Command::new("status")
+                        .about(
+                            "Summarize the mirror state of a tag in one \
+                             view",
+                        )
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(Arg::new("TAG").required(true).help("Image tag")),
+                )
+                .subcommand(
+                    Command::new("tags")
+                        .about("List tags available on the upstream image")
+                        .arg(
+                            Arg::new("IMAGE").required(true).help("Image key"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about(
+                            "Check that every configured upstream is \
+                             reachable",
+                        ),
+                )
+                .subcommand(
+                    Command::new("retag")
+                        .about(
+                            "Copy an existing downstream tag to a new tag",
+                        )
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(
+                            Arg::new("SRC_TAG")
+                                .required(true)
+                                .help("Existing tag"),
+                        )
+                        .arg(
+                            Arg::new("DST_TAG").required(true).help("New tag"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("delete")
+                        .about("Delete a tag from the downstream registry")
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(Arg::new("TAG").required(true).help("Image tag")),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about(
+                            "Delete downstream tags matching a glob \
+                             pattern",
+                        )
+                        .arg(Arg::new("IMAGE").required(true).help("Image key"))
+                        .arg(
+                            Arg::new("GLOB")
+                                .required(true)
+                                .help("Tag pattern, e.g. nightly-*"),
+                        )
+                        .arg(
+                            Arg::new("confirm")
+                                .long("confirm")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Actually delete; without this only \
+                                     the matches are shown",
+                                ),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Delete even when more than \
+                                     prune_max_tags tags match",
+                                ),
+                        ),
+                ),
+        )
+}
+
+/// Build the skopeo copy arguments for an image plus a credential-redacted
+/// copy safe to echo into the room.
+fn copy_args(
+    image_config: &ImageConfig,
+    downstream: &str,
+    src_tag: &str,
+    dest_tag: &str,
+    registry: &Registry,
+    platform: Option<(&str, &str)>,
+) -> (Vec<String>, Vec<String>) {
+    let upstream = &image_config.upstream;
+    let mut command_args = vec!["copy".to_string()];
+    // an explicit --platform takes precedence over the per-image
+    // all_arch setting
+    match platform {
+        Some((os, arch)) => {
+            command_args.push("--override-os".to_string());
+            command_args.push(os.to_string());
+            command_args.push("--override-arch".to_string());
+            command_args.push(arch.to_string());
+        }
+        None if image_config.all_arch() => {
+            command_args.push("--all".to_string())
+        }
+        None => {}
+    }
+    let mut log_args = command_args.clone();
+    for arg in image_config.extra_args() {
+        command_args.push(arg.clone());
+        // redact values of credential-carrying extra args in the copy
+        // echoed into the room, e.g. --src-creds=user:pass
+        log_args.push(match arg.split_once('=') {
+            Some((name, _)) if name.contains("creds") => {
+                format!("{name}=***")
+            }
+            _ => arg.clone(),
+        });
+    }
+    // per-side TLS verification; unset leaves skopeo's default
+    // (verification on)
+    if let Some(verify) = image_config.src_tls_verify(registry) {
+        command_args.push(format!("--src-tls-verify={verify}"));
+        log_args.push(format!("--src-tls-verify={verify}"));
+    }
+    if let Some(verify) = image_config.dest_tls_verify(registry) {
+        command_args.push(format!("--dest-tls-verify={verify}"));
+        log_args.push(format!("--dest-tls-verify={verify}"));
+    }
+    // a digest source is addressed with @, a tag with :
+    let source = if is_digest(src_tag) {
+        format!("docker://{upstream}@{src_tag}")
+    } else {
+        format!("docker://{upstream}:{src_tag}")
+    };
+    command_args.push(source.clone());
+    command_args.push(format!("docker://{downstream}:{dest_tag}"));
+    log_args.push(source);
+    log_args.push(format!("docker://{downstream}:{dest_tag}"));
+    // the configured username/password belong to the downstream
+    // mirror; upstreams stay anonymous unless src credentials are set
+    if let Some(creds) = registry.src_credentials() {
+        command_args.push("--src-creds".to_string());
+        command_args.push(creds);
+        log_args.push("--src-creds".to_string());
+        log_args.push("***".to_string());
+    }
+    if let Some(creds) = registry.credentials() {
+        command_args.push("--dest-creds".to_string());
+        command_args.push(creds);
+        log_args.push("--dest-creds".to_string());
+        log_args.push("***".to_string());
+    }
+    (command_args, log_args)
+}
+
+/// One line of the append-only import audit log.
+#[derive(serde::Serialize)]
+struct AuditEntry<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    sender: &'a str,
+    room: &'a str,
+    image: &'a str,
+    tag: &'a str,
+    success: bool,
+}
+
+/// Append an entry to the audit log if one is configured. Best-effort:
+/// failures are logged, never propagated.
+fn write_audit_entry(state: &BotState, config: &Config, entry: &AuditEntry) {
+    let Some(path) = &config.audit_log_path else {
+        return;
+    };
+    let _guard = state.audit_lock.lock().unwrap();
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| {
+            use std::io::Write;
+            let line = serde_json::to_string(entry).unwrap_or_default();
+            writeln!(file, "{line}")
+        });
+    if let Err(err) = result {
+        tracing::error!("Failed to write audit log {path}: {err:?}");
+    }
+}
+
+/// One parsed line of the audit log, owned unlike [`AuditEntry`] so it
+/// can outlive the file contents. Unknown fields from newer or older
+/// bot versions are ignored.
+#[derive(serde::Deserialize)]
+struct HistoryEntry {
+    timestamp: u64,
+    sender: String,
+    image: String,
+    tag: String,
+    success: bool,
+}
+
+/// Read the newest `limit` audit entries, newest first, optionally
+/// filtered by image key. Best-effort: a missing file counts as empty
+/// and unparseable lines are skipped.
+fn read_audit_history(
+    path: &str,
+    image: Option<&str>,
+    limit: usize,
+) -> Vec<HistoryEntry> {
+    let Ok(data) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<HistoryEntry> = data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|entry: &HistoryEntry| {
+            image.is_none() || image == Some(entry.image.as_str())
+        })
+        .collect();
+    // the log is append-only, so reversing yields newest first
+    entries.reverse();
+    entries.truncate(limit);
+    entries
+}
+
+/// Render an audit timestamp relative to now ("5m ago"); the bot has no
+/// timezone database to print absolute local times with.
+fn format_age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let age = now.saturating_sub(timestamp);
+    if age < 60 {
+        format!("{age}s ago")
+    } else if age < 3600 {
+        format!("{}m ago", age / 60)
+    } else if age < 86400 {
+        format!("{}h ago", age / 3600)
+    } else {
+        format!("{}d ago", age / 86400)
+    }
+}
+
+/// Load the digest cache, a JSON object mapping image:tag to the
+/// upstream digest seen at the last successful import. Best-effort: a
+/// missing or unreadable cache counts as empty.
+fn load_digest_cache(path: &str) -> HashMap<String, String> {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Record `digest` for `key` in the digest cache. Best-effort: failures
+/// are logged, never propagated.
+fn store_digest(path: &str, key: &str, digest: &str) {
+    let mut cache = load_digest_cache(path);
+    cache.insert(key.to_string(), digest.to_string());
+    match serde_json::to_vec_pretty(&cache) {
+        Ok(data) => {
+            if let Err(err) = std::fs::write(path, data) {
+                tracing::error!(
+                    "Failed to write digest cache {path}: {err:?}"
+                );
+            }
+        }
+        Err(err) => {
+            tracing::error!("Failed to serialize digest cache: {err:?}")
+        }
+    }
+}
+
+/// Whether an import argument names a digest rather than a tag:
+/// `sha256:` followed by 64 hex characters.
+fn is_digest(text: &str) -> bool {
+    text.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// Inspect an image reference and return its digest, authenticating
+/// with `creds` (the caller picks the upstream or downstream side).
+/// Best-effort: any failure, including a missing tag, comes back as
+/// None.
+async fn image_digest(
+    registry: &Registry,
+    reference: &str,
+    tag: &str,
+    creds: Option<String>,
+) -> Option<String> {
+    let mut command_args =
+        vec!["inspect".to_string(), format!("docker://{reference}:{tag}")];
+    if let Some(creds) = creds {
+        command_args.push("--creds".to_string());
+        command_args.push(creds);
+    }
+    // bounded like every other skopeo call; a timed-out inspect is
+    // just another failure
+    let output = tokio::time::timeout(
+        Duration::from_secs(registry.skopeo_timeout_secs()),
+        skopeo_command(registry)
+            .args(&command_args)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let info: serde_json::Value =
+        serde_json::from_slice(&output.stdout).ok()?;
+    info["Digest"].as_str().map(ToString::to_string)
+}
+
+/// Levenshtein distance between two short command words, used for
+/// "did you mean" suggestions on typoed subcommands.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// Render the reply for a failed command parse. Typoed subcommands get
+/// a "did you mean" suggestion (or the full help when nothing is
+/// close); a subcommand that was reached but not completed (e.g.
+/// `!otcbot registry`) shows that subcommand's help instead of the
+/// whole top-level help. The
+/// output is wrapped in a markdown code block so clap's column
+/// alignment survives the clients' proportional fonts.
+fn command_error_reply(prefix: &str, words: &[&str], err: &clap::Error) -> String {
+    if err.kind() == clap::error::ErrorKind::InvalidSubcommand {
+        if let Some(clap::error::ContextValue::String(given)) =
+            err.get(clap::error::ContextKind::InvalidSubcommand)
+        {
+            let cmd = otcbot_cmd(prefix);
+            // suggest from the level where parsing failed: registry
+            // subcommands under `registry`, top-level names otherwise
+            let scope = match words.get(1) {
+                Some(&"registry") if words.len() > 2 => {
+                    cmd.find_subcommand("registry").unwrap_or(&cmd)
+                }
+                _ => &cmd,
+            };
+            let suggestion = scope
+                .get_subcommands()
+                .map(|sub| (edit_distance(given, sub.get_name()), sub))
+                .filter(|(distance, _)| *distance <= 2)
+                .min_by_key(|(distance, _)| *distance);
+            return match suggestion {
+                Some((_, sub)) => format!(
+                    "Unknown command `{given}`. Did you mean `{}`?",
+                    sub.get_name()
+                ),
+                None => help_overview(prefix),
+            };
+        }
+    }
+    if err.kind() == clap::error::ErrorKind::MissingSubcommand {
+        let mut cmd = otcbot_cmd(prefix);
+        if let Some(sub) = words
+            .get(1)
+            .and_then(|name| cmd.find_subcommand_mut(*name))
+        {
+            return format!("```\n{}```", sub.render_long_help());
+        }
+    }
+    format!("```\n{}\n```", err.to_string().trim_end())
+}
+
+/// Markdown overview of every command, grouped into general commands
+/// and registry subcommands. Sent for `help` and for a bare prefix
+/// instead of routing through clap's error path, which renders usage
+/// strings rather than something friendly.
+fn help_overview(prefix: &str) -> String {
+    let cmd = otcbot_cmd(prefix);
+    let mut general = String::new();
+    let mut registry = String::new();
+    for sub in cmd.get_subcommands() {
+        if sub.get_name() == "registry" {
+            for reg in sub.get_subcommands() {
+                registry.push_str(&format!(
+                    "- `{prefix} registry {}` — {}\n",
+                    reg.get_name(),
+                    reg.get_about().map(ToString::to_string).unwrap_or_default()
+                ));
+            }
+        } else {
+            general.push_str(&format!(
+                "- `{prefix} {}` — {}\n",
+                sub.get_name(),
+                sub.get_about().map(ToString::to_string).unwrap_or_default()
+            ));
+        }
+    }
+    format!(
+        "**General**\n{general}\n**Registry** (admins only)\n{registry}\
+         \nAppend `--help` to any command for details."
+    )
+}
+
+/// Fill a greeting template's `{name}` and `{mxid}` placeholders.
+fn render_greeting(template: &str, name: &str, mxid: &str) -> String {
+    template.replace("{name}", name).replace("{mxid}", mxid)
+}
+
+/// What a message asks the bot to do. Decided purely from the message,
+/// the sender and the configuration so the routing can be unit tested
+/// without a Matrix connection.
+#[derive(Debug)]
+enum Dispatch {
+    /// Not addressed to the bot.
+    Ignore,
+    /// Reply with plain text.
+    Reply(String),
+    /// Greet the sender with this template; `{name}` and `{mxid}` are
+    /// filled in by the handler, which can fetch the display name.
+    Greet(String),
+    /// Reply with markdown-rendered usage (parse errors and `--help`).
+    Usage(String),
+    /// Reply with the grouped command overview.
+    Help(String),
+    /// The sender or room may not use this command; reply and mark it
+    /// as failed.
+    Deny(String),
+    /// Echo this text back verbatim, as plain text and as markdown.
+    Echo(String),
+    /// Run this parsed command through the async handler.
+    Run(ArgMatches),
+}
+
+/// Route a message body to an intended action. Everything that needs
+/// no network access is decided here; commands with side effects come
+/// back as [`Dispatch::Run`] for `on_room_message` to execute.
+fn dispatch_command(
+    body: &str,
+    sender: &UserId,
+    room_id: &str,
+    config: &Config,
+) -> Dispatch {
+    if body.trim() == "gm" {
+        return match config
+            .greetings
+            .as_ref()
+            .and_then(|greetings| greetings.gm.clone())
+        {
+            Some(template) => Dispatch::Greet(template),
+            None => Dispatch::Reply("gm to you too".to_string()),
+        };
+    }
+    let Some(words) = config
+        .command_prefixes()
+        .iter()
+        .find_map(|prefix| command_words(body, prefix))
+    else {
+        return Dispatch::Ignore;
+    };
+    // handle help and the bare prefix here rather than letting
+    // `subcommand_required(true)` turn them into parse errors
+    if words.len() == 1 || words.get(1) == Some(&"help") {
+        return Dispatch::Help(help_overview(config.command_prefix()));
+    }
+    // echo is routed around clap: word splitting would collapse the
+    // very whitespace a formatting debug aid needs to preserve
+    if words.get(1) == Some(&"echo") {
+        if !config.command_allowed(room_id, "echo") {
+            return Dispatch::Deny(
+                "That command isn't available in this room".to_string(),
+            );
+        }
+        if !config.matrix.is_admin(sender.as_str()) {
+            return Dispatch::Deny(
+                "You are not authorized to run this command".to_string(),
+            );
+        }
+        let after_prefix = body.trim_start()[words[0].len()..].trim_start();
+        let text = after_prefix["echo".len()..]
+            .strip_prefix(' ')
+            .unwrap_or("")
+            .to_string();
+        if text.is_empty() {
+            return Dispatch::Reply("Nothing to echo".to_string());
+        }
+        return Dispatch::Echo(text);
+    }
+    let matches = match otcbot_cmd(config.command_prefix())
+        .try_get_matches_from(words.clone())
+    {
+        Ok(matches) => matches,
+        Err(err) => {
+            // clap renders both parse errors and `--help` this way
+            return Dispatch::Usage(command_error_reply(
+                config.command_prefix(),
+                &words,
+                &err,
+            ));
+        }
+    };
+    let command = matches.subcommand_name().unwrap_or("");
+    if !config.command_allowed(room_id, command) {
+        return Dispatch::Deny(
+            "That command isn't available in this room".to_string(),
+        );
+    }
+    if matches!(command, "leave" | "registry" | "stats" | "restart")
+        && !config.matrix.is_admin(sender.as_str())
+    {
+        return Dispatch::Deny(
+            "You are not authorized to run this command".to_string(),
+        );
+    }
+    Dispatch::Run(matches)
+}
+
+/// Whether the event was sent by the bot's own user, to avoid reacting
+/// to echoed commands and feedback loops.
+fn is_own_message(sender: &UserId, own_user: Option<&UserId>) -> bool {
+    own_user == Some(sender)
+}
+
+/// Split a message body into command tokens if it addresses the bot.
+/// The prefix match is case-insensitive and leading, trailing and
+/// duplicate whitespace (including tabs) is ignored.
+fn command_words<'a>(body: &'a str, prefix: &str) -> Option<Vec<&'a str>> {
+    let mut words = body.split_whitespace();
+    let first = words.next()?;
+    if !first.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let mut result = vec![first];
+    result.extend(words);
+    Some(result)
+}
+
+/// Send a message into the room, logging instead of panicking when the
+/// send fails so a transient error cannot tear down the sync loop. A
+/// rejection for exceeding the homeserver's event size limit is retried
+/// as an attachment, since such an event can never go through as-is.
+async fn send_message(
+    room: &Room,
+    content: RoomMessageEventContent,
+) -> Option<OwnedEventId> {
+    use matrix_sdk::ruma::api::client::error::ErrorKind;
+    let body = content.body().to_string();
+    match room.send(content).await {
+        Ok(response) => Some(response.event_id),
+        Err(err)
+            if matches!(
+                err.client_api_error_kind(),
+                Some(ErrorKind::TooLarge)
+            ) =>
+        {
+            tracing::warn!(
+                "Reply to {} exceeds the event size limit, attaching it \
+                 instead",
+                room.room_id()
+            );
+            if !attach_log(room, "reply", &body).await {
+                return None;
+            }
+            let notice = RoomMessageEventContent::text_plain(
+                "The reply was too large for a message; full output \
+                 attached",
+            );
+            match room.send(notice).await {
+                Ok(response) => Some(response.event_id),
+                Err(err) => {
+                    tracing::error!(
+                        "Failed to send message to {}: {err:?}",
+                        room.room_id()
+                    );
+                    None
+                }
+            }
+        }
+        Err(err) => {
+            tracing::error!(
+                "Failed to send message to {}: {err:?}",
+                room.room_id()
+            );
+            None
+        }
+    }
+}
+
+/// Toggle the typing notice if enabled, logging failures instead of
+/// panicking.
+async fn set_typing(room: &Room, config: &Config, typing: bool) {
+    if !config.matrix.send_typing_notices() {
+        return;
+    }
+    if let Err(err) = room.typing_notice(typing).await {
+        tracing::warn!(
+            "Failed to set typing notice in {}: {err:?}",
+            room.room_id()
+        );
+    }
+}
+
+/// A plain-text result message, prefixed with ✅ or ❌ (unless
+/// `matrix.use_emoji` is off) so outcomes are scannable in chat.
+fn result_message(
+    config: &Config,
+    success: bool,
+    body: String,
+) -> RoomMessageEventContent {
+    if config.matrix.use_emoji() {
+        let prefix = if success { "✅" } else { "❌" };
+        RoomMessageEventContent::text_plain(format!("{prefix} {body}"))
+    } else {
+        RoomMessageEventContent::text_plain(body)
+    }
+}
+
+/// Group `content` under the triggering command as a threaded reply
+/// when `matrix.reply_in_thread` is enabled. Edits to a threaded
+/// message stay in its thread, so only the first message of an edit
+/// chain needs the relation.
+fn threaded(
+    config: &Config,
+    mut content: RoomMessageEventContent,
+    root: Option<&OwnedEventId>,
+) -> RoomMessageEventContent {
+    if let (true, Some(root)) = (config.matrix.reply_in_thread, root) {
+        content.relates_to = Some(Relation::Thread(Thread::plain(
+            root.clone(),
+            root.clone(),
+        )));
+    }
+    content
+}
+
+/// React to the given event with an emoji if enabled, logging failures
+/// instead of panicking.
+async fn react(room: &Room, config: &Config, event_id: &OwnedEventId, key: &str) {
+    if !config.matrix.use_reactions() {
+        return;
+    }
+    let content = ReactionEventContent::new(Annotation::new(
+        event_id.clone(),
+        key.to_string(),
+    ));
+    if let Err(err) = room.send(content).await {
+        tracing::warn!("Failed to react in {}: {err:?}", room.room_id());
+    }
+}
+
+/// Build the skopeo process, honoring `registry.skopeo_runner` so the
+/// binary can run inside a container. The runner words are passed as
+/// argv directly; no shell is involved, so no quoting applies.
+fn skopeo_command(registry: &Registry) -> ProcessCommand {
+    match registry.skopeo_runner.as_deref() {
+        Some([runner, runner_args @ ..]) => {
+            let mut command = ProcessCommand::new(runner);
+            command.args(runner_args).arg(registry.skopeo());
+            command
+        }
+        _ => ProcessCommand::new(registry.skopeo()),
+    }
+}
+
+/// Describe a failed skopeo spawn, pointing at the configured path
+/// when the binary simply is not there.
+fn skopeo_spawn_error(registry: &Registry, err: &std::io::Error) -> String {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        format!(
+            "skopeo binary not found at {}; please install it or set \
+             registry.skopeo_path",
+            registry.skopeo()
+        )
+    } else {
+        format!("Failed to run skopeo: {err}")
+    }
+}
+
+/// Run skopeo to completion, turning a spawn failure into a message
+/// that can be sent to the room as-is. Bounded by
+/// `skopeo_timeout_secs` like copies, so a hung registry cannot wedge
+/// the handler.
+async fn run_skopeo(
+    registry: &Registry,
+    command_args: &[String],
+) -> Result<std::process::Output, String> {
+    let deadline = Duration::from_secs(registry.skopeo_timeout_secs());
+    tokio::time::timeout(
+        deadline,
+        skopeo_command(registry)
+            .args(command_args)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| {
+        format!("skopeo timed out after {}s", deadline.as_secs())
+    })?
+    .map_err(|err| skopeo_spawn_error(registry, &err))
+}
+
+/// Minimal glob matching supporting `*` (any run of characters) and
+/// `?` (any single character), enough for tag patterns like `v1.2.*`
+/// or `nightly-?`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn inner(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((b'*', rest)) => {
+                (0..=text.len()).any(|skip| inner(rest, &text[skip..]))
+            }
+            Some((b'?', rest)) => {
+                !text.is_empty() && inner(rest, &text[1..])
+            }
+            Some((expected, rest)) => {
+                text.first() == Some(expected) && inner(rest, &text[1..])
+            }
+        }
+    }
+    inner(pattern.as_bytes(), text.as_bytes())
+}
+
+/// Compile `registry.redact_patterns`, skipping (with a warning)
+/// patterns that fail to parse so one typo does not disable the others.
+fn compile_redactions(patterns: &[String]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(err) => {
+                tracing::warn!(
+                    "Ignoring invalid redact pattern {pattern:?}: {err}"
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Replace every match of the redaction patterns with `***`, so tokens
+/// a registry embeds in URLs or headers do not leak into the room.
+fn redact_log(log: &str, redactions: &[Regex]) -> String {
+    let mut log = log.to_string();
+    for pattern in redactions {
+        log = pattern.replace_all(&log, "***").into_owned();
+    }
+    log
+}
+
+/// Keep only the last `max_lines` lines of skopeo output so a chatty
+/// copy cannot push the room message over Matrix's event size limit.
+/// The tail is kept because errors come last.
+fn truncate_log(log: &str, max_lines: usize) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+    if lines.len() <= max_lines {
+        return log.to_string();
+    }
+    let omitted = lines.len() - max_lines;
+    format!(
+        "...(truncated, {omitted} lines omitted)\n{}",
+        lines[omitted..].join("\n")
+    )
+}
+
+/// Parse a human-readable size as skopeo prints it ("140.5MiB",
+/// "1.2GB") into bytes.
+fn parse_size(text: &str) -> Option<f64> {
+    // longer suffixes first so "MiB" is not matched as "B"
+    const UNITS: [(&str, f64); 7] = [
+        ("KiB", 1024.0),
+        ("MiB", 1024.0 * 1024.0),
+        ("GiB", 1024.0 * 1024.0 * 1024.0),
+        ("KB", 1000.0),
+        ("MB", 1_000_000.0),
+        ("GB", 1_000_000_000.0),
+        ("B", 1.0),
+    ];
+    let text = text.trim();
+    for (suffix, factor) in UNITS {
+        if let Some(number) = text.strip_suffix(suffix) {
+            return number.trim().parse::<f64>().ok().map(|n| n * factor);
+        }
+    }
+    None
+}
+
+/// Render bytes with the binary units skopeo itself uses.
+fn format_size(bytes: f64) -> String {
+    const STEPS: [(&str, f64); 3] = [
+        ("GiB", 1024.0 * 1024.0 * 1024.0),
+        ("MiB", 1024.0 * 1024.0),
+        ("KiB", 1024.0),
+    ];
+    for (unit, factor) in STEPS {
+        if bytes >= factor {
+            return format!("{:.1}{unit}", bytes / factor);
+        }
+    }
+    format!("{bytes:.0}B")
+}
+
+/// Render a duration the way a human would say it: "42s" below two
+/// minutes, "7m 02s" above.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 120 {
+        format!("{secs}s")
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Total size of all blobs a finished copy transferred, summed from the
+/// progress lines in the log. None when skopeo printed no byte counts.
+fn copied_size_from_log(log: &str) -> Option<f64> {
+    let mut totals: HashMap<&str, f64> = HashMap::new();
+    for line in log.lines() {
+        let Some(rest) = line.trim().strip_prefix("Copying blob ") else {
+            continue;
+        };
+        let Some(digest) = rest.split_whitespace().next() else {
+            continue;
+        };
+        if let Some((_, right)) = rest.rsplit_once('/') {
+            if let Some(total) = parse_size(right) {
+                totals.insert(digest, total);
+            }
+        }
+    }
+    if totals.is_empty() {
+        None
+    } else {
+        Some(totals.values().sum())
+    }
+}
+
+/// Best-effort summary of a copy log so far, e.g.
+/// "Copying: 3/7 blobs, 140.0MiB/512.0MiB". Tracks the latest progress
+/// line per blob; byte counts are included only when skopeo printed
+/// them. Returns None while the log contains no blob lines, in which
+/// case the caller keeps streaming plain output.
+fn parse_copy_progress(log: &str) -> Option<String> {
+    // digest -> (copied, total, done)
+    let mut blobs: HashMap<&str, (f64, f64, bool)> = HashMap::new();
+    for line in log.lines() {
+        let Some(rest) = line.trim().strip_prefix("Copying blob ") else {
+            continue;
+        };
+        let Some(digest) = rest.split_whitespace().next() else {
+            continue;
+        };
+        let entry = blobs.entry(digest).or_insert((0.0, 0.0, false));
+        if rest.trim_end().ends_with("done") {
+            entry.2 = true;
+        } else if let Some((left, right)) = rest.rsplit_once('/') {
+            // progress lines end in "<copied> / <total>"
+            let copied =
+                left.split_whitespace().last().and_then(parse_size);
+            if let (Some(copied), Some(total)) =
+                (copied, parse_size(right))
+            {
+                entry.0 = copied;
+                entry.1 = total;
+            }
+        }
+    }
+    if blobs.is_empty() {
+        return None;
+    }
+    let done = blobs.values().filter(|(_, _, done)| *done).count();
+    let mut summary = format!("Copying: {done}/{} blobs", blobs.len());
+    let total: f64 = blobs.values().map(|(_, total, _)| total).sum();
+    if total > 0.0 {
+        let copied: f64 = blobs
+            .values()
+            .map(|(copied, total, done)| if *done { *total } else { *copied })
+            .sum();
+        summary.push_str(&format!(
+            ", {}/{}",
+            format_size(copied),
+            format_size(total)
+        ));
+    }
+    Some(summary)
+}
+
+/// Upload a full skopeo log as a text attachment. Returns false when
+/// the upload failed and the caller should fall back to inline output.
+async fn attach_log(room: &Room, label: &str, log: &str) -> bool {
+    let filename = format!(
+        "{}.log.txt",
+        label.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    );
+    match room
+        .send_attachment(
+            &filename,
+            &mime::TEXT_PLAIN,
+            log.as_bytes().to_vec(),
+            AttachmentConfig::new(),
+        )
+        .await
+    {
+        Ok(_) => true,
+        Err(err) => {
+            tracing::warn!("Failed to upload log attachment: {err:?}");
+            false
+        }
+    }
+}
+
+/// Run one skopeo copy, streaming its output into the room by editing a
+/// progress message every few seconds. Returns whether the copy
+/// succeeded; spawn failures and timeouts count as failure and are
+/// reported to the room.
+async fn stream_copy(
+    room: &Room,
+    config: &Config,
+    command_args: &[String],
+    log_args: &[String],
+    label: &str,
+    thread_root: Option<&OwnedEventId>,
+) -> bool {
+    let started = Instant::now();
+    let mut child = match skopeo_command(&config.registry)
+        .args(command_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            tracing::error!("Failed to spawn skopeo: {err:?}");
+            let content = threaded(
+                config,
+                RoomMessageEventContent::text_plain(
+                    skopeo_spawn_error(&config.registry, &err),
+                ),
+                thread_root,
+            );
+            send_message(room, content).await;
+            return false;
+        }
+    };
+    let header = format!(
+        "{} {}",
+        config.registry.skopeo_display(),
+        log_args.join(" ")
+    );
+    let progress_event_id = send_message(
+        room,
+        threaded(
+            config,
+            RoomMessageEventContent::text_plain(format!(
+                "Importing {label}...\n\n{header}"
+            )),
+            thread_root,
+        ),
+    )
+    .await;
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let redactions =
+        compile_redactions(config.registry.redact_patterns());
+    let mut log = String::new();
+    let mut stdout_done = false;
+    let mut stderr_done = false;
+    let mut ticker = tokio::time::interval(Duration::from_secs(5));
+    // the first tick of an interval fires immediately
+    ticker.tick().await;
+    let deadline = Duration::from_secs(config.registry.skopeo_timeout_secs());
+    let copy = async {
+        while !(stdout_done && stderr_done) {
+            tokio::select! {
+                line = stdout.next_line(), if !stdout_done => match line {
+                    Ok(Some(line)) => {
+                        log.push_str(&redact_log(&line, &redactions));
+                        log.push('\n');
+                    }
+                    _ => stdout_done = true,
+                },
+                line = stderr.next_line(), if !stderr_done => match line {
+                    Ok(Some(line)) => {
+                        log.push_str(&redact_log(&line, &redactions));
+                        log.push('\n');
+                    }
+                    _ => stderr_done = true,
+                },
+                _ = ticker.tick() => {
+                    if let Some(event_id) = &progress_event_id {
+                        // a parsed progress summary reads better than
+                        // raw output, but raw output beats nothing
+                        let body = match parse_copy_progress(&log) {
+                            Some(progress) => format!(
+                                "Importing {label}...\n\n{header}\n\
+                                 {progress}"
+                            ),
+                            None => format!(
+                                "Importing {label}...\n\n{header}\n{}",
+                                truncate_log(
+                                    &log,
+                                    config.registry.max_log_lines(),
+                                )
+                            ),
+                        };
+                        let update =
+                            RoomMessageEventContent::text_plain(body)
+                                .make_replacement(
+                                    ReplacementMetadata::new(
+                                        event_id.clone(),
+                                        None,
+                                    ),
+                                    None,
+                                );
+                        send_message(room, update).await;
+                    }
+                }
+            }
+        }
+        child.wait().await
+    };
+    let status = match tokio::time::timeout(deadline, copy).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(err)) => {
+            tracing::error!("Failed to wait for skopeo: {err:?}");
+            let mut content = RoomMessageEventContent::text_plain(format!(
+                "Import of {label} failed: could not collect the skopeo \
+                 exit status ({err})"
+            ));
+            if let Some(event_id) = progress_event_id {
+                content = content.make_replacement(
+                    ReplacementMetadata::new(event_id, None),
+                    None,
+                );
+            }
+            send_message(room, content).await;
+            return false;
+        }
+        Err(_) => {
+            if let Err(err) = child.kill().await {
+                tracing::warn!("Failed to kill skopeo: {err:?}");
+            }
+            let mut content = RoomMessageEventContent::text_plain(format!(
+                "Import of {label} timed out after {} seconds",
+                config.registry.skopeo_timeout_secs()
+            ));
+            if let Some(event_id) = progress_event_id {
+                content = content.make_replacement(
+                    ReplacementMetadata::new(event_id, None),
+                    None,
+                );
+            }
+            send_message(room, content).await;
+            return false;
+        }
+    };
+    let summary = if status.success() {
+        let mut summary = format!(
+            "Imported {label} in {}",
+            format_duration(started.elapsed())
+        );
+        if let Some(bytes) = copied_size_from_log(&log) {
+            summary.push_str(&format!(" ({})", format_size(bytes)));
+        }
+        summary
+    } else {
+        format!("Import of {label} failed")
+    };
+    let body = match config.registry.log_attachment_threshold {
+        Some(threshold)
+            if log.len() > threshold
+                && attach_log(room, label, &log).await =>
+        {
+            format!("{summary}\n\n{header}\n(full log attached)")
+        }
+        _ => format!(
+            "{summary}\n\n{header}\n{}",
+            truncate_log(&log, config.registry.max_log_lines())
+        ),
+    };
+    let mut content = result_message(config, status.success(), body);
+    if let Some(event_id) = progress_event_id {
+        content = content
+            .make_replacement(ReplacementMetadata::new(event_id, None), None);
+    }
+    send_message(room, content).await;
+    status.success()
+}
+
+/// Queue state of an import job, shown by `registry jobs`.
+#[derive(Clone, Copy, PartialEq)]
+enum JobStatus {
+    Queued,
+    Running,
+}
+
+/// A job visible in `registry jobs`, from enqueueing until it finishes.
+struct JobInfo {
+    /// The `image:tag` being imported.
+    job: String,
+    /// Who started the import.
+    sender: OwnedUserId,
+    status: JobStatus,
+    /// Event ID of the queue reply, edited on state changes.
+    event_id: Option<OwnedEventId>,
+    /// Aborts the job task. Dropping the task kills a running skopeo
+    /// child (kill_on_drop) and releases its semaphore permit.
+    abort: Option<AbortHandle>,
+}
+
+/// Everything an import job needs once it leaves the queue.
+struct ImportJob {
+    id: u64,
+    image: String,
+    tag: String,
+    /// Root of the reply thread when `reply_in_thread` is enabled;
+    /// None for imports triggered outside a room, e.g. by the webhook.
+    thread_root: Option<OwnedEventId>,
+    /// Tag used on the downstream side; equals `tag` unless `--as` was
+    /// given.
+    dest_tag: String,
+    platform: Option<(String, String)>,
+    /// Upstream digest to record in the cache on success, when a digest
+    /// cache is configured.
+    current_digest: Option<String>,
+}
+
+/// Update the queue reply for a job by editing the original message,
+/// falling back to a fresh message when that reply never made it out.
+async fn update_job_message(
+    room: &Room,
+    event_id: &Option<OwnedEventId>,
+    text: String,
+) {
+    let mut content = RoomMessageEventContent::text_plain(text);
+    if let Some(event_id) = event_id {
+        content = content.make_replacement(
+            ReplacementMetadata::new(event_id.clone(), None),
+            None,
+        );
+    }
+    send_message(room, content).await;
+}
+
+/// Sign a downstream reference with cosign, reporting the outcome to
+/// the room separately from the copy result.
+async fn sign_image(
+    room: &Room,
+    config: &Config,
+    sign: &SignConfig,
+    reference: &str,
+) {
+    let mut command = ProcessCommand::new(sign.cosign());
+    command.args(["sign", "--yes", "--key", &sign.key_path, reference]);
+    if let Some(password) = &sign.password {
+        command.env("COSIGN_PASSWORD", password);
+    }
+    let content = match command.output().await {
+        Ok(output) if output.status.success() => {
+            RoomMessageEventContent::text_plain(format!("Signed {reference}"))
+        }
+        Ok(output) => {
+            let stderr = redact_log(
+                &String::from_utf8_lossy(&output.stderr),
+                &compile_redactions(config.registry.redact_patterns()),
+            );
+            RoomMessageEventContent::text_plain(format!(
+                "Signing of {reference} failed\n\n{}",
+                truncate_log(&stderr, config.registry.max_log_lines())
+            ))
+        }
+        Err(err) => RoomMessageEventContent::text_plain(format!(
+            "Failed to run cosign: {err}"
+        )),
+    };
+    send_message(room, content).await;
+}
+
+/// Wait for an import slot, then run all copies of one job, keeping the
+/// queue entry and the queue reply up to date as the job moves from
+/// queued to running to done.
+async fn run_import_job(
+    import: ImportJob,
+    queued_event_id: Option<OwnedEventId>,
+    room: Room,
+    config: Config,
+    state: BotState,
+    sender: OwnedUserId,
+) {
+    let ImportJob {
+        id,
+        image,
+        thread_root,
+        tag,
+        dest_tag,
+        platform,
+        current_digest,
+    } = import;
+    let job = if dest_tag == tag {
+        format!("{image}:{tag}")
+    } else {
+        format!("{image}:{tag} -> :{dest_tag}")
+    };
+    let permit = match state.import_slots.acquire().await {
+        Ok(permit) => permit,
+        // the semaphore is never closed
+        Err(_) => {
+            state.jobs.lock().unwrap().remove(&id);
+            state.in_flight.lock().unwrap().remove(&job);
+            return;
+        }
+    };
+    if let Some(info) = state.jobs.lock().unwrap().get_mut(&id) {
+        info.status = JobStatus::Running;
+    }
+    update_job_message(
+        &room,
+        &queued_event_id,
+        format!("Job #{id} running: {job}"),
+    )
+    .await;
+    let image_config = &config.registry.images[&image];
+    set_typing(&room, &config, true).await;
+    let mut failed: Vec<String> = Vec::new();
+    for target in image_config.downstream.targets() {
+        let (command_args, log_args) = copy_args(
+            image_config,
+            target,
+            &tag,
+            &dest_tag,
+            &config.registry,
+            platform
+                .as_ref()
+                .map(|(os, arch)| (os.as_str(), arch.as_str())),
+        );
+        let copy_started = Instant::now();
+        let success = stream_copy(
+            &room,
+            &config,
+            &command_args,
+            &log_args,
+            &format!("{image}:{tag} -> {target}:{dest_tag}"),
+            thread_root.as_ref(),
+        )
+        .await;
+        state
+            .metrics
+            .record_import(success, copy_started.elapsed().as_secs_f64());
+        if success {
+            // signing only makes sense for images that actually arrived
+            if let Some(sign) = &config.registry.sign {
+                sign_image(&room, &config, sign, &format!("{target}:{dest_tag}"))
+                    .await;
+            }
+        } else {
+            failed.push(target.to_string());
+        }
+    }
+    drop(permit);
+    set_typing(&room, &config, false).await;
+    state.jobs.lock().unwrap().remove(&id);
+    state.in_flight.lock().unwrap().remove(&job);
+    write_audit_entry(
+        &state,
+        &config,
+        &AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            sender: sender.as_str(),
+            room: room.room_id().as_str(),
+            image: &image,
+            tag: &tag,
+            success: failed.is_empty(),
+        },
+    );
+    if failed.is_empty() {
+        if let (Some(cache_path), Some(digest)) =
+            (&config.registry.digest_cache_path, &current_digest)
+        {
+            store_digest(cache_path, &job, digest);
+        }
+        update_job_message(
+            &room,
+            &queued_event_id,
+            format!("Job #{id} finished: {job}"),
+        )
+        .await;
+    } else {
+        update_job_message(
+            &room,
+            &queued_event_id,
+            format!("Job #{id} failed: {job} ({})", failed.join(", ")),
+        )
+        .await;
+    }
+}
+
+/// Queue one bulk import the same way a chat `import` would: register
+/// the image:tag as in flight, honor the queueing policy and spawn
+/// [`run_import_job`]. `import.id` is overwritten with a freshly
+/// allocated job id, which is also returned; the error is a short
+/// reason suitable for a per-image result line. Progress lands in
+/// `room` as fresh messages since there is no queue reply to edit.
+fn enqueue_bulk_import(
+    mut import: ImportJob,
+    room: &Room,
+    config: &Config,
+    state: &BotState,
+    sender: &UserId,
+) -> Result<u64, &'static str> {
+    let job = format!("{}:{}", import.image, import.tag);
+    if !state.in_flight.lock().unwrap().insert(job.clone()) {
+        return Err("already in progress");
+    }
+    if !config.registry.queue_imports()
+        && state.import_slots.available_permits() == 0
+    {
+        state.in_flight.lock().unwrap().remove(&job);
+        return Err("too many imports running");
+    }
+    let id = state.next_job_id.fetch_add(1, Ordering::SeqCst) + 1;
+    import.id = id;
+    state.jobs.lock().unwrap().insert(
+        id,
+        JobInfo {
+            job,
+            sender: sender.to_owned(),
+            status: JobStatus::Queued,
+            event_id: None,
+            abort: None,
+        },
+    );
+    let handle = tokio::spawn(run_import_job(
+        import,
+        None,
+        room.clone(),
+        config.clone(),
+        state.clone(),
+        sender.to_owned(),
+    ));
+    if let Some(info) = state.jobs.lock().unwrap().get_mut(&id) {
+        info.abort = Some(handle.abort_handle());
+    }
+    Ok(id)
+}
+
+/// Mirror every scheduled image/tag pair once and post the results to
+/// the notify room. Copies run sequentially like `import-all`.
+async fn run_scheduled_sync(client: &Client, state: &BotState) {
+    let config = state.shared_config.read().unwrap().clone();
+    let Some(schedule) = &config.schedule else {
+        return;
+    };
+    let deadline =
+        Duration::from_secs(config.registry.skopeo_timeout_secs());
+    let mut lines = Vec::new();
+    for entry in &schedule.entries {
+        let Some(image_config) = config.registry.images.get(&entry.image)
+        else {
+            lines.push(format!(
+                "- {}:{}: not configured",
+                entry.image, entry.tag
+            ));
+            continue;
+        };
+        for target in image_config.downstream.targets() {
+            let (command_args, _) = copy_args(
+                image_config,
+                target,
+                &entry.tag,
+                &entry.tag,
+                &config.registry,
+                None,
+            );
+            let copy_started = Instant::now();
+            let result = tokio::time::timeout(
+                deadline,
+                skopeo_command(&config.registry)
+                    .args(&command_args)
+                    .kill_on_drop(true)
+                    .output(),
+            )
+            .await;
+            let success =
+                matches!(&result, Ok(Ok(output)) if output.status.success());
+            state.metrics.record_import(
+                success,
+                copy_started.elapsed().as_secs_f64(),
+            );
+            lines.push(format!(
+                "- {}:{} -> {target}: {}",
+                entry.image,
+                entry.tag,
+                if success { "ok" } else { "failed" }
+            ));
+        }
+    }
+    if let Some(room) = notify_room(client, &config) {
+        let content = RoomMessageEventContent::text_markdown(format!(
+            "Scheduled sync results:\n\n{}",
+            lines.join("\n")
+        ));
+        send_message(&room, content).await;
+    }
+}
+
+/// Handle the `registry` subcommand.
+async fn otcbot_registry(
+    args: &ArgMatches,
+    room: &Room,
+    config: &Config,
+    state: &BotState,
+    sender: &UserId,
+    thread_root: &OwnedEventId,
+) -> anyhow::Result<()> {
+    match args.subcommand() {
+        Some(("import", import_args)) => {
+            let image: &String = import_args.get_one("IMAGE").unwrap();
+            let tag: &String = import_args.get_one("TAG").unwrap();
+            let Some((image, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            if tag.starts_with("sha256:") {
+                if !is_digest(tag) {
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "Invalid digest {tag}: expected sha256: \
+                             followed by 64 hex characters"
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    return Ok(());
+                }
+                // a digest cannot be pushed as a tag, so the downstream
+                // side needs an explicit one
+                if import_args.get_one::<String>("as").is_none() {
+                    let content = RoomMessageEventContent::text_plain(
+                        "Importing by digest requires --as <tag> for \
+                         the downstream side",
+                    );
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    return Ok(());
+                }
+            }
+            if let Some(allowed) = &image_config.allowed_tags {
+                if !allowed
+                    .iter()
+                    .any(|pattern| glob_match(pattern, tag))
+                {
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "Tag {tag} is not permitted for {image}"
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    return Ok(());
+                }
+            }
+            let platform = match import_args.get_one::<String>("platform") {
+                Some(value) => match value.split_once('/') {
+                    Some((os, arch))
+                        if !os.is_empty()
+                            && !arch.is_empty()
+                            && !arch.contains('/') =>
+                    {
+                        Some((os, arch))
+                    }
+                    _ => {
+                        let content =
+                            RoomMessageEventContent::text_plain(format!(
+                                "Invalid platform {value}: expected \
+                                 <os>/<arch>, e.g. linux/amd64"
+                            ));
+                        let content =
+                            threaded(config, content, Some(thread_root));
+                        send_message(room, content).await;
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+            let dest_tag: &String =
+                import_args.get_one("as").unwrap_or(tag);
+            if import_args.get_flag("dry-run") {
+                let mut lines = Vec::new();
+                for target in image_config.downstream.targets() {
+                    let (_, log_args) = copy_args(
+                        image_config,
+                        target,
+                        tag,
+                        dest_tag,
+                        &config.registry,
+                        platform,
+                    );
+                    lines.push(format!(
+                        "`{} {}`",
+                        config.registry.skopeo_display(),
+                        log_args.join(" ")
+                    ));
+                }
+                let content = RoomMessageEventContent::text_markdown(
+                    format!("Would run:\n\n{}", lines.join("\n")),
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let job = if dest_tag == tag {
+                format!("{image}:{tag}")
+            } else {
+                format!("{image}:{tag} -> :{dest_tag}")
+            };
+            // only inspect the upstream when a cache is configured; the
+            // digest is also what gets stored after a successful import.
+            // Imports with a custom destination tag bypass the cache, it
+            // is keyed by the shared image:tag only.
+            let mut current_digest: Option<String> = None;
+            if dest_tag == tag {
+                if let Some(cache_path) = &config.registry.digest_cache_path
+                {
+                    current_digest = image_digest(
+                        &config.registry,
+                        &image_config.upstream,
+                        tag,
+                        config.registry.src_credentials(),
+                    )
+                    .await;
+                    if !import_args.get_flag("force") {
+                        if let Some(digest) = &current_digest {
+                            if load_digest_cache(cache_path).get(&job)
+                                == Some(digest)
+                            {
+                                let content =
+                                    RoomMessageEventContent::text_plain(
+                                        format!(
+                                            "{job} already up to date \
+                                             (digest unchanged)"
+                                        ),
+                                    );
+                                let content =
+                                    threaded(config, content, Some(thread_root));
+                                send_message(room, content).await;
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+            if !state.in_flight.lock().unwrap().insert(job.clone()) {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Import of {job} already in progress"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            if !config.registry.queue_imports()
+                && state.import_slots.available_permits() == 0
+            {
+                state.in_flight.lock().unwrap().remove(&job);
+                let content = RoomMessageEventContent::text_plain(
+                    "Too many imports running, try again later",
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let id = state.next_job_id.fetch_add(1, Ordering::SeqCst) + 1;
+            let queued_event_id = send_message(
+                room,
+                threaded(
+                    config,
+                    RoomMessageEventContent::text_plain(format!(
+                        "Queued as job #{id}: {job}"
+                    )),
+                    Some(thread_root),
+                ),
+            )
+            .await;
+            state.jobs.lock().unwrap().insert(
+                id,
+                JobInfo {
+                    job: job.clone(),
+                    sender: sender.to_owned(),
+                    status: JobStatus::Queued,
+                    event_id: queued_event_id.clone(),
+                    abort: None,
+                },
+            );
+            let import = ImportJob {
+                id,
+                image: image.to_string(),
+                thread_root: Some(thread_root.clone()),
+                tag: tag.clone(),
+                dest_tag: dest_tag.clone(),
+                platform: platform
+                    .map(|(os, arch)| (os.to_string(), arch.to_string())),
+                current_digest,
+            };
+            // the job runs detached so the handler is free again; the
+            // semaphore inside the task enforces the concurrency limit
+            // and hands out slots in queue order
+            let handle = tokio::spawn(run_import_job(
+                import,
+                queued_event_id,
+                room.clone(),
+                config.clone(),
+                state.clone(),
+                sender.to_owned(),
+            ));
+            // a job that already finished has removed itself by now
+            if let Some(info) = state.jobs.lock().unwrap().get_mut(&id) {
+                info.abort = Some(handle.abort_handle());
+            }
+            Ok(())
+        }
+        Some(("copy", adhoc_args)) => {
+            // registry commands are admin-only as a whole, which is
+            // what makes this arbitrary-reference copy acceptable
+            let src: &String = adhoc_args.get_one("SRC_REF").unwrap();
+            let dst: &String = adhoc_args.get_one("DST_REF").unwrap();
+            fn looks_like_reference(reference: &str) -> bool {
+                !reference.is_empty()
+                    && reference.chars().all(|c| {
+                        c.is_ascii_alphanumeric() || "./-_:@".contains(c)
+                    })
+            }
+            if let Some(bad) = [src, dst]
+                .into_iter()
+                .find(|reference| !looks_like_reference(reference))
+            {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "{bad} does not look like an image reference"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let mut command_args = vec![
+                "copy".to_string(),
+                format!("docker://{src}"),
+                format!("docker://{dst}"),
+            ];
+            if let Some(verify) = config.registry.src_tls_verify {
+                command_args.push(format!("--src-tls-verify={verify}"));
+            }
+            if let Some(verify) = config.registry.dest_tls_verify {
+                command_args.push(format!("--dest-tls-verify={verify}"));
+            }
+            let mut log_args = command_args.clone();
+            if let Some(creds) = config.registry.src_credentials() {
+                command_args.push("--src-creds".to_string());
+                command_args.push(creds);
+                log_args.push("--src-creds".to_string());
+                log_args.push("***".to_string());
+            }
+            if let Some(creds) = config.registry.credentials() {
+                command_args.push("--dest-creds".to_string());
+                command_args.push(creds);
+                log_args.push("--dest-creds".to_string());
+                log_args.push("***".to_string());
+            }
+            set_typing(room, config, true).await;
+            stream_copy(
+                room,
+                config,
+                &command_args,
+                &log_args,
+                &format!("{src} -> {dst}"),
+                Some(thread_root),
+            )
+            .await;
+            set_typing(room, config, false).await;
+            Ok(())
+        }
+        Some(("jobs", _)) => {
+            let content = {
+                let jobs = state.jobs.lock().unwrap();
+                if jobs.is_empty() {
+                    RoomMessageEventContent::text_plain(
+                        "No imports queued or running",
+                    )
+                } else {
+                    let lines: Vec<String> = jobs
+                        .iter()
+                        .map(|(id, info)| {
+                            format!(
+                                "- #{id} {} — {} ({})",
+                                info.job,
+                                match info.status {
+                                    JobStatus::Queued => "queued",
+                                    JobStatus::Running => "running",
+                                },
+                                info.sender
+                            )
+                        })
+                        .collect();
+                    RoomMessageEventContent::text_markdown(format!(
+                        "Current import jobs:\n\n{}",
+                        lines.join("\n")
+                    ))
+                }
+            };
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("cancel", cancel_args)) => {
+            let raw: &String = cancel_args.get_one("JOB_ID").unwrap();
+            let Ok(id) = raw.trim_start_matches('#').parse::<u64>() else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Invalid job ID {raw}"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let removed = {
+                let mut jobs = state.jobs.lock().unwrap();
+                match jobs.get(&id) {
+                    None => Err(format!("No job #{id}")),
+                    Some(info)
+                        if info.sender != *sender
+                            && !config.matrix.is_admin(sender.as_str()) =>
+                    {
+                        Err(format!(
+                            "Only {} or an admin may cancel job #{id}",
+                            info.sender
+                        ))
+                    }
+                    Some(_) => Ok(jobs.remove(&id).unwrap()),
+                }
+            };
+            match removed {
+                Ok(info) => {
+                    if let Some(abort) = &info.abort {
+                        // aborting the task kills a running skopeo child
+                        // and releases its semaphore permit, so queued
+                        // jobs move up
+                        abort.abort();
+                    }
+                    state.in_flight.lock().unwrap().remove(&info.job);
+                    update_job_message(
+                        room,
+                        &info.event_id,
+                        format!("Job #{id} cancelled: {}", info.job),
+                    )
+                    .await;
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "Cancelled job #{id} ({})",
+                            info.job
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                }
+                Err(reason) => {
+                    let content =
+                        RoomMessageEventContent::text_plain(reason);
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                }
+            }
+            Ok(())
+        }
+        Some(("history", history_args)) => {
+            const HISTORY_LIMIT: usize = 10;
+            let Some(path) = &config.audit_log_path else {
+                let content = RoomMessageEventContent::text_plain(
+                    "No audit log configured (audit_log_path)",
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let image = history_args.get_one::<String>("IMAGE");
+            let entries = read_audit_history(
+                path,
+                image.map(String::as_str),
+                HISTORY_LIMIT,
+            );
+            if entries.is_empty() {
+                let content = RoomMessageEventContent::text_plain(
+                    "No import history yet",
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let mut reply = String::from(
+                "| when | who | import | result |\n|---|---|---|---|\n",
+            );
+            for entry in entries {
+                reply.push_str(&format!(
+                    "| {} | {} | {}:{} | {} |\n",
+                    format_age(entry.timestamp),
+                    entry.sender,
+                    entry.image,
+                    entry.tag,
+                    if entry.success { "✅" } else { "❌" },
+                ));
+            }
+            let content = RoomMessageEventContent::text_markdown(reply);
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("inspect", inspect_args)) => {
+            let image: &String = inspect_args.get_one("IMAGE").unwrap();
+            let tag: &String = inspect_args.get_one("TAG").unwrap();
+            let Some((_, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let reference =
+                format!("docker://{}:{}", image_config.upstream, tag);
+            set_typing(room, config, true).await;
+            let mut command_args =
+                vec!["inspect".to_string(), reference.clone()];
+            // the reference is the upstream image, so only the src
+            // credentials apply; the mirror ones would get a 401
+            if let Some(creds) = config.registry.src_credentials() {
+                command_args.push("--creds".to_string());
+                command_args.push(creds);
+            }
+            let output = match run_skopeo(&config.registry, &command_args)
+                .await
+            {
+                Ok(output) => output,
+                Err(reason) => {
+                    set_typing(room, config, false).await;
+                    let content =
+                        RoomMessageEventContent::text_plain(reason);
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    return Ok(());
+                }
+            };
+            set_typing(room, config, false).await;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let reason = stderr
+                    .lines()
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                    .unwrap_or("unknown error");
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Failed to inspect {reference}: {}",
+                    reason.trim()
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let content = match serde_json::from_slice::<serde_json::Value>(
+                &output.stdout,
+            ) {
+                Ok(info) => {
+                    let digest =
+                        info["Digest"].as_str().unwrap_or("unknown");
+                    let created =
+                        info["Created"].as_str().unwrap_or("unknown");
+                    let architecture =
+                        info["Architecture"].as_str().unwrap_or("unknown");
+                    let layers = info["Layers"]
+                        .as_array()
+                        .map(|layers| layers.len())
+                        .unwrap_or(0);
+                    RoomMessageEventContent::text_markdown(format!(
+                        "**{reference}**\n\n\
+                         - Digest: `{digest}`\n\
+                         - Created: {created}\n\
+                         - Architecture: {architecture}\n\
+                         - Layers: {layers}"
+                    ))
+                }
+                Err(err) => RoomMessageEventContent::text_plain(format!(
+                    "Could not parse skopeo output: {err}"
+                )),
+            };
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("diff", diff_args)) => {
+            let image: &String = diff_args.get_one("IMAGE").unwrap();
+            let tag: &String = diff_args.get_one("TAG").unwrap();
+            let Some((image, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            set_typing(room, config, true).await;
+            let upstream = image_digest(
+                &config.registry,
+                &image_config.upstream,
+                tag,
+                config.registry.src_credentials(),
+            )
+            .await;
+            let Some(upstream) = upstream else {
+                set_typing(room, config, false).await;
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Could not inspect upstream {}:{tag}",
+                    image_config.upstream
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let mut lines = Vec::new();
+            let mut out_of_sync = false;
+            for target in image_config.downstream.targets() {
+                match image_digest(
+                    &config.registry,
+                    target,
+                    tag,
+                    config.registry.credentials(),
+                )
+                .await
+                {
+                    None => {
+                        out_of_sync = true;
+                        lines.push(format!("- {target}: not mirrored"));
+                    }
+                    Some(digest) if digest == upstream => {
+                        lines.push(format!(
+                            "- {target}: in sync (`{digest}`)"
+                        ));
+                    }
+                    Some(digest) => {
+                        out_of_sync = true;
+                        lines.push(format!(
+                            "- {target}: differs\n  - upstream: \
+                             `{upstream}`\n  - downstream: `{digest}`"
+                        ));
+                    }
+                }
+            }
+            set_typing(room, config, false).await;
+            let mut reply = format!(
+                "Digest diff for {image}:{tag}:\n\n{}",
+                lines.join("\n")
+            );
+            if out_of_sync {
+                reply.push_str(&format!(
+                    "\n\nRun `{} registry import {image} {tag}` to update",
+                    config.command_prefix()
+                ));
+            }
+            let content = threaded(
+                config,
+                RoomMessageEventContent::text_markdown(reply),
+                Some(thread_root),
+            );
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("status", status_args)) => {
+            let image: &String = status_args.get_one("IMAGE").unwrap();
+            let tag: &String = status_args.get_one("TAG").unwrap();
+            let Some((image, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            set_typing(room, config, true).await;
+            let upstream = image_digest(
+                &config.registry,
+                &image_config.upstream,
+                tag,
+                config.registry.src_credentials(),
+            )
+            .await;
+            let mut lines = vec![format!(
+                "- upstream {}: {}",
+                image_config.upstream,
+                match &upstream {
+                    Some(digest) => format!("present (`{digest}`)"),
+                    None => "not found".to_string(),
+                }
+            )];
+            for target in image_config.downstream.targets() {
+                let line = match (
+                    image_digest(
+                        &config.registry,
+                        target,
+                        tag,
+                        config.registry.credentials(),
+                    )
+                    .await,
+                    &upstream,
+                ) {
+                    (None, _) => format!("- {target}: not mirrored"),
+                    (Some(digest), Some(up)) if digest == *up => {
+                        format!("- {target}: in sync")
+                    }
+                    (Some(digest), _) => {
+                        format!("- {target}: differs (`{digest}`)")
+                    }
+                };
+                lines.push(line);
+            }
+            set_typing(room, config, false).await;
+            // the newest audit entry for this exact tag, if any
+            let last_import = config.audit_log_path.as_deref().and_then(
+                |path| {
+                    read_audit_history(path, Some(image), usize::MAX)
+                        .into_iter()
+                        .find(|entry| entry.tag == *tag)
+                },
+            );
+            lines.push(match last_import {
+                Some(entry) => format!(
+                    "- last import: {} by {} ({})",
+                    format_age(entry.timestamp),
+                    entry.sender,
+                    if entry.success { "succeeded" } else { "failed" }
+                ),
+                None => "- last import: none recorded".to_string(),
+            });
+            let content = RoomMessageEventContent::text_markdown(format!(
+                "Status of {image}:{tag}:\n\n{}",
+                lines.join("\n")
+            ));
+            let content = threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("import-all", import_all_args)) => {
+            let tag: &String = import_all_args.get_one("TAG").unwrap();
+            let mut keys: Vec<String> =
+                config.registry.images.keys().cloned().collect();
+            keys.sort();
+            if keys.is_empty() {
+                let content =
+                    RoomMessageEventContent::text_plain("No images configured");
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            set_typing(room, config, true).await;
+            let total = keys.len();
+            let mut queued: Vec<String> = Vec::new();
+            let mut not_queued: Vec<String> = Vec::new();
+            let mut skipped = 0;
+            let mut disallowed: Vec<&str> = Vec::new();
+            // the copies run as regular jobs, so they respect the
+            // global concurrency limit, show up in `registry jobs` and
+            // leave the handler (and the sync loop) free
+            for key in &keys {
+                let image_config = &config.registry.images[key];
+                // the tag allow-list applies here just as it does to a
+                // single import; a bulk run must not sneak past it
+                if let Some(allowed) = &image_config.allowed_tags {
+                    if !allowed
+                        .iter()
+                        .any(|pattern| glob_match(pattern, tag))
+                    {
+                        disallowed.push(key.as_str());
+                        continue;
+                    }
+                }
+                let mut current_digest: Option<String> = None;
+                if let Some(cache_path) = &config.registry.digest_cache_path
+                {
+                    current_digest = image_digest(
+                        &config.registry,
+                        &image_config.upstream,
+                        tag,
+                        config.registry.src_credentials(),
+                    )
+                    .await;
+                    if let Some(digest) = &current_digest {
+                        if load_digest_cache(cache_path)
+                            .get(&format!("{key}:{tag}"))
+                            == Some(digest)
+                        {
+                            skipped += 1;
+                            continue;
+                        }
+                    }
+                }
+                let import = ImportJob {
+                    id: 0,
+                    image: key.clone(),
+                    thread_root: Some(thread_root.clone()),
+                    tag: tag.clone(),
+                    dest_tag: tag.clone(),
+                    platform: None,
+                    current_digest,
+                };
+                match enqueue_bulk_import(import, room, config, state, sender)
+                {
+                    Ok(id) => queued.push(format!("#{id} {key}")),
+                    Err(reason) => {
+                        not_queued.push(format!("{key} ({reason})"))
+                    }
+                }
+            }
+            set_typing(room, config, false).await;
+            let mut summary = if queued.is_empty() {
+                format!("No imports queued at tag {tag}")
+            } else {
+                format!(
+                    "Queued {} of {total} imports at tag {tag}: {}",
+                    queued.len(),
+                    queued.join(", ")
+                )
+            };
+            if skipped > 0 {
+                summary.push_str(&format!(
+                    ", {skipped} already up to date"
+                ));
+            }
+            if !disallowed.is_empty() {
+                summary.push_str(&format!(
+                    ", tag not permitted for {}",
+                    disallowed.join(", ")
+                ));
+            }
+            if !not_queued.is_empty() {
+                summary.push_str(&format!(
+                    ", not queued: {}",
+                    not_queued.join(", ")
+                ));
+            }
+            let content = threaded(
+                config,
+                RoomMessageEventContent::text_plain(summary),
+                Some(thread_root),
+            );
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("tags", tags_args)) => {
+            // cap the reply so a busy upstream repo does not flood the room
+            const MAX_TAGS: usize = 50;
+            let image: &String = tags_args.get_one("IMAGE").unwrap();
+            let Some((_, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let reference = format!("docker://{}", image_config.upstream);
+            set_typing(room, config, true).await;
+            let mut command_args =
+                vec!["list-tags".to_string(), reference.clone()];
+            // listing happens against the upstream repository
+            if let Some(creds) = config.registry.src_credentials() {
+                command_args.push("--creds".to_string());
+                command_args.push(creds);
+            }
+            let output = match run_skopeo(&config.registry, &command_args)
+                .await
+            {
+                Ok(output) => output,
+                Err(reason) => {
+                    set_typing(room, config, false).await;
+                    let content =
+                        RoomMessageEventContent::text_plain(reason);
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    return Ok(());
+                }
+            };
+            set_typing(room, config, false).await;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let reason = stderr
+                    .lines()
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                    .unwrap_or("unknown error");
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Failed to list tags of {reference}: {}",
+                    reason.trim()
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let content = match serde_json::from_slice::<serde_json::Value>(
+                &output.stdout,
+            ) {
+                Ok(info) => {
+                    let tags: Vec<&str> = info["Tags"]
+                        .as_array()
+                        .map(|tags| {
+                            tags.iter().filter_map(|tag| tag.as_str()).collect()
+                        })
+                        .unwrap_or_default();
+                    let mut reply = format!(
+                        "Tags of {reference}:\n\n{}",
+                        tags.iter()
+                            .take(MAX_TAGS)
+                            .map(|tag| format!("- {tag}"))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    );
+                    if tags.len() > MAX_TAGS {
+                        reply.push_str(&format!(
+                            "\n\n...and {} more",
+                            tags.len() - MAX_TAGS
+                        ));
+                    }
+                    RoomMessageEventContent::text_markdown(reply)
+                }
+                Err(err) => RoomMessageEventContent::text_plain(format!(
+                    "Could not parse skopeo output: {err}"
+                )),
+            };
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("verify", _)) => {
+            // enough parallelism to finish quickly without hammering
+            // the upstreams
+            const VERIFY_CONCURRENCY: usize = 4;
+            if config.registry.images.is_empty() {
+                let content = RoomMessageEventContent::text_plain(
+                    "No images configured",
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            set_typing(room, config, true).await;
+            let mut keys: Vec<String> =
+                config.registry.images.keys().cloned().collect();
+            keys.sort();
+            let checks = Arc::new(Semaphore::new(VERIFY_CONCURRENCY));
+            let mut tasks = Vec::new();
+            for key in keys {
+                let registry = config.registry.clone();
+                let upstream = registry.images[&key].upstream.clone();
+                let checks = checks.clone();
+                tasks.push(tokio::spawn(async move {
+                    let _permit = checks.acquire().await.unwrap();
+                    let mut command_args = vec![
+                        "list-tags".to_string(),
+                        format!("docker://{upstream}"),
+                    ];
+                    if let Some(creds) = registry.src_credentials() {
+                        command_args.push("--creds".to_string());
+                        command_args.push(creds);
+                    }
+                    let status =
+                        match run_skopeo(&registry, &command_args).await {
+                            Ok(output) if output.status.success() => {
+                                "✅ reachable".to_string()
+                            }
+                            Ok(output) => {
+                                let stderr = String::from_utf8_lossy(
+                                    &output.stderr,
+                                );
+                                format!(
+                                    "❌ {}",
+                                    stderr
+                                        .lines()
+                                        .rev()
+                                        .find(|line| {
+                                            !line.trim().is_empty()
+                                        })
+                                        .unwrap_or("unknown error")
+                                        .trim()
+                                )
+                            }
+                            Err(reason) => format!("❌ {reason}"),
+                        };
+                    (key, upstream, status)
+                }));
+            }
+            let mut table = String::from(
+                "| Image | Upstream | Status |\n| --- | --- | --- |\n",
+            );
+            for task in tasks {
+                if let Ok((key, upstream, status)) = task.await {
+                    table.push_str(&format!(
+                        "| {key} | {upstream} | {status} |\n"
+                    ));
+                }
+            }
+            set_typing(room, config, false).await;
+            let content = RoomMessageEventContent::text_markdown(table);
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("retag", retag_args)) => {
+            let image: &String = retag_args.get_one("IMAGE").unwrap();
+            let src_tag: &String = retag_args.get_one("SRC_TAG").unwrap();
+            let dst_tag: &String = retag_args.get_one("DST_TAG").unwrap();
+            let Some((_, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            set_typing(room, config, true).await;
+            for downstream in image_config.downstream.targets() {
+                let src = format!("docker://{downstream}:{src_tag}");
+                let dst = format!("docker://{downstream}:{dst_tag}");
+                let mut command_args = vec![
+                    "copy".to_string(),
+                    "--all".to_string(),
+                    src.clone(),
+                    dst.clone(),
+                ];
+                if let Some(creds) = config.registry.credentials() {
+                    command_args.push("--src-creds".to_string());
+                    command_args.push(creds.clone());
+                    command_args.push("--dest-creds".to_string());
+                    command_args.push(creds);
+                }
+                let output = match run_skopeo(
+                    &config.registry,
+                    &command_args,
+                )
+                .await
+                {
+                    Ok(output) => output,
+                    Err(reason) => {
+                        let content =
+                            RoomMessageEventContent::text_plain(reason);
+                        let content =
+                            threaded(config, content, Some(thread_root));
+                        send_message(room, content).await;
+                        break;
+                    }
+                };
+                let content = if output.status.success() {
+                    result_message(
+                        config,
+                        true,
+                        format!("Retagged {src} as {dst}"),
+                    )
+                } else {
+                    let stderr = redact_log(
+                        &String::from_utf8_lossy(&output.stderr),
+                        &compile_redactions(
+                            config.registry.redact_patterns(),
+                        ),
+                    );
+                    result_message(
+                        config,
+                        false,
+                        format!(
+                            "Retagging {src} as {dst} failed\n\n{}",
+                            truncate_log(
+                                &stderr,
+                                config.registry.max_log_lines(),
+                            )
+                        ),
+                    )
+                };
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+            }
+            set_typing(room, config, false).await;
+            Ok(())
+        }
+        Some(("delete", delete_args)) => {
+            let image: &String = delete_args.get_one("IMAGE").unwrap();
+            let tag: &String = delete_args.get_one("TAG").unwrap();
+            let Some((_, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            set_typing(room, config, true).await;
+            for downstream in image_config.downstream.targets() {
+                let target = format!("docker://{downstream}:{tag}");
+                send_message(
+                    room,
+                    threaded(
+                        config,
+                        RoomMessageEventContent::text_plain(format!(
+                            "Deleting {target}"
+                        )),
+                        Some(thread_root),
+                    ),
+                )
+                .await;
+                let mut command_args =
+                    vec!["delete".to_string(), target.clone()];
+                if let Some(creds) = config.registry.credentials() {
+                    command_args.push("--creds".to_string());
+                    command_args.push(creds);
+                }
+                let output = match run_skopeo(
+                    &config.registry,
+                    &command_args,
+                )
+                .await
+                {
+                    Ok(output) => output,
+                    Err(reason) => {
+                        let content =
+                            RoomMessageEventContent::text_plain(reason);
+                        let content =
+                            threaded(config, content, Some(thread_root));
+                        send_message(room, content).await;
+                        break;
+                    }
+                };
+                let content = if output.status.success() {
+                    result_message(config, true, format!("Deleted {target}"))
+                } else {
+                    let stderr = redact_log(
+                        &String::from_utf8_lossy(&output.stderr),
+                        &compile_redactions(
+                            config.registry.redact_patterns(),
+                        ),
+                    );
+                    result_message(
+                        config,
+                        false,
+                        format!(
+                            "Deletion of {target} failed\n\n{}",
+                            truncate_log(
+                                &stderr,
+                                config.registry.max_log_lines(),
+                            )
+                        ),
+                    )
+                };
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+            }
+            set_typing(room, config, false).await;
+            Ok(())
+        }
+        Some(("prune", prune_args)) => {
+            let image: &String = prune_args.get_one("IMAGE").unwrap();
+            let pattern: &String = prune_args.get_one("GLOB").unwrap();
+            let confirm = prune_args.get_flag("confirm");
+            let force = prune_args.get_flag("force");
+            let Some((_, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not known to me"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            set_typing(room, config, true).await;
+            for downstream in image_config.downstream.targets() {
+                let reference = format!("docker://{downstream}");
+                let mut command_args =
+                    vec!["list-tags".to_string(), reference.clone()];
+                if let Some(creds) = config.registry.credentials() {
+                    command_args.push("--creds".to_string());
+                    command_args.push(creds);
+                }
+                let output = match run_skopeo(
+                    &config.registry,
+                    &command_args,
+                )
+                .await
+                {
+                    Ok(output) => output,
+                    Err(reason) => {
+                        let content =
+                            RoomMessageEventContent::text_plain(reason);
+                        let content =
+                            threaded(config, content, Some(thread_root));
+                        send_message(room, content).await;
+                        break;
+                    }
+                };
+                if !output.status.success() {
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "Failed to list tags of {reference}"
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    continue;
+                }
+                let tags: Vec<String> =
+                    serde_json::from_slice::<serde_json::Value>(
+                        &output.stdout,
+                    )
+                    .ok()
+                    .and_then(|info| {
+                        info["Tags"].as_array().map(|tags| {
+                            tags.iter()
+                                .filter_map(|tag| tag.as_str())
+                                .filter(|tag| glob_match(pattern, tag))
+                                .map(ToString::to_string)
+                                .collect()
+                        })
+                    })
+                    .unwrap_or_default();
+                if tags.is_empty() {
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "No tags of {downstream} match {pattern}"
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    continue;
+                }
+                if !confirm {
+                    let content =
+                        RoomMessageEventContent::text_markdown(format!(
+                            "Would delete {} tags of {downstream}: {}\n\n\
+                             Re-run with `--confirm` to delete them",
+                            tags.len(),
+                            tags.join(", ")
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    continue;
+                }
+                let limit = config.registry.prune_max_tags();
+                if tags.len() > limit && !force {
+                    let content =
+                        RoomMessageEventContent::text_plain(format!(
+                            "{} tags of {downstream} match {pattern}, \
+                             more than the prune limit of {limit}; \
+                             re-run with --force to delete them anyway",
+                            tags.len()
+                        ));
+                    let content =
+                        threaded(config, content, Some(thread_root));
+                    send_message(room, content).await;
+                    continue;
+                }
+                let mut deleted = Vec::new();
+                let mut failed = Vec::new();
+                for tag in &tags {
+                    let mut command_args = vec![
+                        "delete".to_string(),
+                        format!("{reference}:{tag}"),
+                    ];
+                    if let Some(creds) = config.registry.credentials() {
+                        command_args.push("--creds".to_string());
+                        command_args.push(creds);
+                    }
+                    match run_skopeo(&config.registry, &command_args)
+                        .await
+                    {
+                        Ok(output) if output.status.success() => {
+                            deleted.push(tag.as_str());
+                        }
+                        _ => failed.push(tag.as_str()),
+                    }
+                }
+                let mut summary = format!(
+                    "Pruned {} tags of {downstream}: {}",
+                    deleted.len(),
+                    deleted.join(", ")
+                );
+                if !failed.is_empty() {
+                    summary.push_str(&format!(
+                        "\nFailed to delete: {}",
+                        failed.join(", ")
+                    ));
+                }
+                let content =
+                    RoomMessageEventContent::text_plain(summary);
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+            }
+            set_typing(room, config, false).await;
+            Ok(())
+        }
+        Some(("mirror-from-file", mirror_args)) => {
+            let words: Vec<&String> =
+                mirror_args.get_many("LINES").unwrap().collect();
+            // the body is split on any whitespace, so each pasted
+            // `image tag` line arrives as a pair of words
+            if !words.len().is_multiple_of(2) {
+                let content = RoomMessageEventContent::text_plain(
+                    "Expected `image tag` pairs, got an odd number of \
+                     words",
+                );
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            }
+            let mut results = Vec::new();
+            // each pair becomes a regular job, sharing the queue (and
+            // the duplicate protection) with single imports
+            for pair in words.chunks(2) {
+                let (image, tag) = (pair[0].as_str(), pair[1].as_str());
+                let Some((image, image_config)) =
+                    config.registry.resolve_image(image)
+                else {
+                    results
+                        .push(format!("- {image} {tag}: not configured"));
+                    continue;
+                };
+                if let Some(allowed) = &image_config.allowed_tags {
+                    if !allowed
+                        .iter()
+                        .any(|pattern| glob_match(pattern, tag))
+                    {
+                        results.push(format!(
+                            "- {image} {tag}: tag not permitted"
+                        ));
+                        continue;
+                    }
+                }
+                let import = ImportJob {
+                    id: 0,
+                    image: image.to_string(),
+                    thread_root: Some(thread_root.clone()),
+                    tag: tag.to_string(),
+                    dest_tag: tag.to_string(),
+                    platform: None,
+                    current_digest: None,
+                };
+                match enqueue_bulk_import(import, room, config, state, sender)
+                {
+                    Ok(id) => results.push(format!(
+                        "- {image} {tag}: queued as job #{id}"
+                    )),
+                    Err(reason) => {
+                        results.push(format!("- {image} {tag}: {reason}"))
+                    }
+                }
+            }
+            let content = RoomMessageEventContent::text_markdown(format!(
+                "Mirror queue:\n\n{}",
+                results.join("\n")
+            ));
+            let content = threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("list", _)) => {
+            let content = if config.registry.images.is_empty() {
+                RoomMessageEventContent::text_plain("No images configured")
+            } else {
+                let mut keys: Vec<&String> =
+                    config.registry.images.keys().collect();
+                keys.sort();
+                let mut table = String::from(
+                    "| Image | Aliases | Upstream | Downstream |\n\
+                     | --- | --- | --- | --- |\n",
+                );
+                for key in keys {
+                    let image_config = &config.registry.images[key];
+                    table.push_str(&format!(
+                        "| {} | {} | {} | {} |\n",
+                        key,
+                        image_config.aliases().join(", "),
+                        image_config.upstream,
+                        image_config.downstream.targets().join(", ")
+                    ));
+                }
+                RoomMessageEventContent::text_markdown(table)
+            };
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("show", show_args)) => {
+            let image: &String = show_args.get_one("IMAGE").unwrap();
+            let Some((image, image_config)) =
+                config.registry.resolve_image(image)
+            else {
+                let content = RoomMessageEventContent::text_plain(format!(
+                    "Image {image} is not configured"
+                ));
+                let content =
+                    threaded(config, content, Some(thread_root));
+                send_message(room, content).await;
+                return Ok(());
+            };
+            let mut reply = format!(
+                "**{image}**\n- upstream: {}\n- downstream: {}\n\
+                 - all architectures: {}",
+                image_config.upstream,
+                image_config.downstream.targets().join(", "),
+                image_config.all_arch(),
+            );
+            if !image_config.aliases().is_empty() {
+                reply.push_str(&format!(
+                    "\n- aliases: {}",
+                    image_config.aliases().join(", ")
+                ));
+            }
+            if !image_config.extra_args().is_empty() {
+                reply.push_str(&format!(
+                    "\n- extra args: `{}`",
+                    image_config.extra_args().join(" ")
+                ));
+            }
+            let content = RoomMessageEventContent::text_markdown(reply);
+            let content = threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        Some(("reload", _)) => {
+            let old_images: HashSet<String> = state
+                .shared_config
+                .read()
+                .unwrap()
+                .registry
+                .images
+                .keys()
+                .cloned()
+                .collect();
+            // loading runs secret commands and blocks on them; keep
+            // that off the async runtime
+            let paths = state.config_paths.clone();
+            let loaded = tokio::task::spawn_blocking(move || {
+                Config::from_config_files(&paths)
+            })
+            .await
+            .unwrap_or_else(|err| {
+                Err(anyhow::anyhow!("config reload panicked: {err}"))
+            });
+            let content = match loaded {
+                Ok(new_config) => {
+                    let new_images: HashSet<String> = new_config
+                        .registry
+                        .images
+                        .keys()
+                        .cloned()
+                        .collect();
+                    let mut added: Vec<String> =
+                        new_images.difference(&old_images).cloned().collect();
+                    added.sort();
+                    let mut removed: Vec<String> =
+                        old_images.difference(&new_images).cloned().collect();
+                    removed.sort();
+                    let mut reply = format!(
+                        "Reloaded config, {} images configured",
+                        new_images.len()
+                    );
+                    if !added.is_empty() {
+                        reply.push_str(&format!(
+                            "\nAdded: {}",
+                            added.join(", ")
+                        ));
+                    }
+                    if !removed.is_empty() {
+                        reply.push_str(&format!(
+                            "\nRemoved: {}",
+                            removed.join(", ")
+                        ));
+                    }
+                    *state.shared_config.write().unwrap() = new_config;
+                    tracing::info!(
+                        "Reloaded config from {}",
+                        state.config_paths.join(", ")
+                    );
+                    RoomMessageEventContent::text_plain(reply)
+                }
+                Err(err) => RoomMessageEventContent::text_plain(format!(
+                    "Config reload failed, keeping old config: {err:#}"
+                )),
+            };
+            let content =
+                threaded(config, content, Some(thread_root));
+            send_message(room, content).await;
+            Ok(())
+        }
+        // clap's subcommand_required makes this unreachable
+        _ => anyhow::bail!("unknown registry subcommand"),
+    }
+}
+
+/// Auto-join rooms the bot is invited to.
+async fn on_stripped_state_member(
+    room_member: StrippedRoomMemberEvent,
+    client: Client,
+    room: Room,
+    config: Ctx<SharedConfig>,
+) {
+    if room_member.state_key != client.user_id().unwrap() {
+        return;
+    }
+
+    let (mut delay, max_delay, accept) = {
+        let config = config.read().unwrap();
+        let accept = match config.matrix.autojoin_policy() {
+            AutojoinPolicy::All => true,
+            AutojoinPolicy::AdminsOnly => {
+                config.matrix.is_admin(room_member.sender.as_str())
+            }
+            AutojoinPolicy::None => false,
+        };
+        (
+            config.matrix.autojoin_base_delay_secs(),
+            config.matrix.autojoin_max_delay_secs(),
+            accept,
+        )
+    };
+    if !accept {
+        tracing::warn!(
+            "Declining invite to {} from {} per autojoin_policy",
+            room.room_id(),
+            room_member.sender
+        );
+        if let Err(err) = room.leave().await {
+            tracing::warn!(
+                "Failed to reject invite to {}: {err:?}",
+                room.room_id()
+            );
+        }
+        return;
+    }
+    tokio::spawn(async move {
+        tracing::info!("Autojoining room {}", room.room_id());
+
+        while let Err(err) = room.join().await {
+            // retry autojoin due to synapse sending invites, before the
+            // invited user can join for more information see
+            // https://github.com/matrix-org/synapse/issues/4345
+            tracing::warn!(
+                "Failed to join room {} ({err:?}), retrying in {delay}s",
+                room.room_id()
+            );
+
+            sleep(Duration::from_secs(delay)).await;
+            delay *= 2;
+
+            if delay > max_delay {
+                tracing::error!("Can't join room {} ({err:?})", room.room_id());
+                break;
+            }
+        }
+        tracing::info!("Successfully joined room {}", room.room_id());
+    });
+}
+
+/// Join every room listed in `matrix.rooms`, each in its own task with
+/// the same retry/backoff pattern as invite autojoin.
+fn join_configured_rooms(client: &Client, config: &Config) {
+    let base_delay = config.matrix.autojoin_base_delay_secs();
+    let max_delay = config.matrix.autojoin_max_delay_secs();
+    for room in &config.matrix.rooms {
+        let room = room.clone();
+        let client = client.clone();
+        tokio::spawn(async move {
+            let room_id = match RoomOrAliasId::parse(&room) {
+                Ok(room_id) => room_id,
+                Err(err) => {
+                    tracing::error!(
+                        "Invalid room in matrix.rooms {room}: {err:?}"
+                    );
+                    return;
+                }
+            };
+            tracing::info!("Joining configured room {room}");
+            let mut delay = base_delay;
+            loop {
+                match client.join_room_by_id_or_alias(&room_id, &[]).await {
+                    Ok(_) => {
+                        tracing::info!(
+                            "Successfully joined configured room {room}"
+                        );
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Failed to join configured room {room} \
+                             ({err:?}), retrying in {delay}s"
+                        );
+                        sleep(Duration::from_secs(delay)).await;
+                        delay *= 2;
+                        if delay > max_delay {
+                            tracing::error!(
+                                "Can't join configured room {room} ({err:?})"
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// React to messages in joined rooms.
+async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    config: Ctx<SharedConfig>,
+    state: Ctx<BotState>,
+) {
+    if room.state() != RoomState::Joined {
+        return;
+    }
+    if is_own_message(&event.sender, client.user_id()) {
+        return;
+    }
+    // work on a snapshot so a concurrent reload cannot change the config
+    // mid-command
+    let config = config.read().unwrap().clone();
+    // Commands are parsed from m.text and m.emote messages; formatted
+    // (HTML) messages still populate the plain `body`, which is all we
+    // read. Other message types are ignored.
+    let body = match &event.content.msgtype {
+        MessageType::Text(text_content) => text_content.body.clone(),
+        MessageType::Emote(emote_content) => emote_content.body.clone(),
+        other => {
+            tracing::debug!("Ignoring message of type {}", other.msgtype());
+            return;
+        }
+    };
+
+    if config.matrix.send_read_receipts() {
+        if let Err(err) = room
+            .send_single_receipt(
+                ReceiptType::Read,
+                ReceiptThread::Unthreaded,
+                event.event_id.clone(),
+            )
+            .await
+        {
+            tracing::warn!(
+                "Failed to send read receipt in {}: {err:?}",
+                room.room_id()
+            );
+        }
+    }
+
+    // rate limiting applies to anything addressed to the bot, before
+    // any work is spent parsing it
+    if config
+        .command_prefixes()
+        .iter()
+        .any(|prefix| command_words(&body, prefix).is_some())
+    {
+        // commands replayed long after they were sent (e.g. a backlog
+        // synced after downtime) are dropped rather than executed late
+        let age = event
+            .origin_server_ts
+            .to_system_time()
+            .and_then(|sent| sent.elapsed().ok());
+        if let Some(age) = age {
+            if age.as_secs() > config.max_command_age_secs() {
+                tracing::info!(
+                    "Ignoring stale command from {} ({}s old)",
+                    event.sender,
+                    age.as_secs()
+                );
+                return;
+            }
+        }
+        if let Some(wait) = state
+            .check_rate_limit(&event.sender, config.command_rate_limit())
+        {
+            let content = RoomMessageEventContent::text_plain(format!(
+                "Rate limit exceeded, try again in {wait}s"
+            ));
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+            return;
+        }
+    }
+
+    match dispatch_command(
+        &body,
+        &event.sender,
+        room.room_id().as_str(),
+        &config,
+    ) {
+        Dispatch::Ignore => {}
+        Dispatch::Reply(text) => {
+            let content = RoomMessageEventContent::text_plain(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+        }
+        Dispatch::Greet(template) => {
+            // prefer the room display name; fall back to the MXID when
+            // the member cannot be fetched
+            let name = match room.get_member(&event.sender).await {
+                Ok(Some(member)) => member
+                    .display_name()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| event.sender.to_string()),
+                _ => event.sender.to_string(),
+            };
+            let text = render_greeting(
+                &template,
+                &name,
+                event.sender.as_str(),
+            );
+            let content = RoomMessageEventContent::text_plain(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+        }
+        Dispatch::Usage(text) => {
+            let content = RoomMessageEventContent::text_markdown(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+        }
+        Dispatch::Help(text) => {
+            state.metrics.record_command("help");
+            let content = RoomMessageEventContent::text_markdown(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+        }
+        Dispatch::Deny(text) => {
+            let content = RoomMessageEventContent::text_plain(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+            react(&room, &config, &event.event_id, "❌").await;
+        }
+        Dispatch::Echo(text) => {
+            state.metrics.record_command("echo");
+            // once as plain text and once through the markdown
+            // renderer, to compare how a client formats each
+            let content = RoomMessageEventContent::text_plain(text.clone());
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+            let content = RoomMessageEventContent::text_markdown(text);
+            let content =
+                threaded(&config, content, Some(&event.event_id));
+            send_message(&room, content).await;
+        }
+        Dispatch::Run(matches) => {
+            let command =
+                matches.subcommand_name().unwrap_or("").to_string();
+            let span = tracing::info_span!(
+                "command",
+                sender = %event.sender,
+                room = %room.room_id(),
+                command,
+            );
+            state.metrics.record_command(&command);
+            react(&room, &config, &event.event_id, "👀").await;
+            let success = async {
+                match matches.subcommand() {
+                    Some(("party", _)) => {
+                        // a per-room cooldown, separate from the
+                        // per-user rate limit, so one room cannot be
+                        // flooded with confetti
+                        let cooldown = Duration::from_secs(
+                            config.party_cooldown_secs(),
+                        );
+                        let on_cooldown = {
+                            let mut party_times =
+                                state.party_times.lock().unwrap();
+                            match party_times.get(room.room_id()) {
+                                Some(at) if at.elapsed() < cooldown => {
+                                    true
+                                }
+                                _ => {
+                                    party_times.insert(
+                                        room.room_id().to_owned(),
+                                        Instant::now(),
+                                    );
+                                    false
+                                }
+                            }
+                        };
+                        if on_cooldown {
+                            let content =
+                                RoomMessageEventContent::text_plain(
+                                    "still partying, cool down 🥳",
+                                );
+                            let content =
+                                threaded(&config, content, Some(&event.event_id));
+                            send_message(&room, content).await;
+                            return Some(false);
+                        }
+                        let content = RoomMessageEventContent::text_plain(
+                            "🎉🎊🥳 let's PARTY!! 🥳🎊🎉",
+                        );
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("ping", _)) => {
+                        // origin_server_ts is set by the sender's
+                        // homeserver; with skewed clocks it can lie in
+                        // the future, in which case we stay silent about
+                        // the latency instead of reporting nonsense
+                        let latency = event
+                            .origin_server_ts
+                            .to_system_time()
+                            .and_then(|sent| sent.elapsed().ok());
+                        let content = RoomMessageEventContent::text_plain(
+                            match latency {
+                                Some(latency) => format!(
+                                    "pong ({}ms)",
+                                    latency.as_millis()
+                                ),
+                                None => "pong".to_string(),
+                            },
+                        );
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("status", _)) => {
+                        let uptime = state.started.elapsed().as_secs();
+                        let last_sync =
+                            match *state.last_sync.lock().unwrap() {
+                                Some(at) => match at.elapsed() {
+                                    Ok(ago) => {
+                                        format!("{}s ago", ago.as_secs())
+                                    }
+                                    Err(_) => "just now".to_string(),
+                                },
+                                None => "never".to_string(),
+                            };
+                        let content =
+                            RoomMessageEventContent::text_plain(format!(
+                                "Uptime: {}h {}m {}s\nJoined rooms: {}\n\
+                                 Last successful sync: {last_sync}",
+                                uptime / 3600,
+                                (uptime % 3600) / 60,
+                                uptime % 60,
+                                client.joined_rooms().len(),
+                            ));
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("version", _)) => {
+                        let content =
+                            RoomMessageEventContent::text_plain(format!(
+                                "otcbot {} ({})",
+                                env!("CARGO_PKG_VERSION"),
+                                env!("OTCBOT_GIT_SHA"),
+                            ));
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("whoami", _)) => {
+                        let user_id = client
+                            .user_id()
+                            .map(ToString::to_string)
+                            .unwrap_or_else(|| "unknown".to_string());
+                        let device_id = client
+                            .device_id()
+                            .map(ToString::to_string)
+                            .unwrap_or_else(|| "unknown".to_string());
+                        let content =
+                            RoomMessageEventContent::text_plain(format!(
+                                "User: {user_id}\nDevice: {device_id}\n\
+                                 Homeserver: {}",
+                                client.homeserver(),
+                            ));
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("stats", _)) => {
+                        let stats = state.metrics.command_stats();
+                        let mut table = String::from(
+                            "| Command | Runs | Succeeded | Failed |\n\
+                             | --- | --- | --- | --- |\n",
+                        );
+                        for (name, runs, succeeded, failed) in stats {
+                            table.push_str(&format!(
+                                "| {name} | {runs} | {succeeded} | \
+                                 {failed} |\n"
+                            ));
+                        }
+                        let content =
+                            RoomMessageEventContent::text_markdown(table);
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        Some(true)
+                    }
+                    Some(("restart", _)) => {
+                        let content = RoomMessageEventContent::text_plain(
+                            "Restarting session...",
+                        );
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        // the session is torn down from the main loop;
+                        // nothing may be sent after this, including the
+                        // finish reaction
+                        state.restart.notify_one();
+                        None
+                    }
+                    Some(("leave", _)) => {
+                        let content = RoomMessageEventContent::text_plain(
+                            "Leaving room, goodbye!",
+                        );
+                        let content =
+                            threaded(&config, content, Some(&event.event_id));
+                        send_message(&room, content).await;
+                        // nothing may be sent to the room after this,
+                        // including the finish reaction
+                        if let Err(err) = room.leave().await {
+                            tracing::error!(
+                                "Failed to leave room {}: {err:?}",
+                                room.room_id()
+                            );
+                        }
+                        None
+                    }
+                    Some(("registry", registry_args)) => {
+                        match otcbot_registry(
+                            registry_args,
+                            &room,
+                            &config,
+                            &state,
+                            &event.sender,
+                            &event.event_id,
+                        )
+                        .await
+                        {
+                            Ok(()) => Some(true),
+                            Err(err) => {
+                                tracing::error!(
+                                    "Registry command failed: {err:?}"
+                                );
+                                let content =
+                                    RoomMessageEventContent::text_plain(
+                                        "Something went wrong, check \
+                                         the bot logs for details",
+                                    );
+                                let content =
+                                    threaded(&config, content, Some(&event.event_id));
+                                send_message(&room, content).await;
+                                Some(false)
+                            }
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            .instrument(span)
+            .await;
+            if let Some(success) = success {
+                state.metrics.record_command_result(&command, success);
+                react(
+                    &room,
+                    &config,
+                    &event.event_id,
+                    if success { "✅" } else { "❌" },
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Whether a sync error is worth retrying. Authentication errors are
+/// fatal: retrying with the same credentials cannot succeed.
+fn is_recoverable_sync_error(err: &matrix_sdk::Error) -> bool {
+    use matrix_sdk::ruma::api::client::error::ErrorKind;
+    !matches!(
+        err.client_api_error_kind(),
+        Some(ErrorKind::UnknownToken { .. })
+    )
+}
+
+/// Resolve the configured notify room, if any, logging why it cannot be
+/// used instead of panicking.
+fn notify_room(client: &Client, config: &Config) -> Option<Room> {
+    let notify_room = config.matrix.notify_room.as_ref()?;
+    match RoomId::parse(notify_room) {
+        Ok(room_id) => match client.get_room(&room_id) {
+            Some(room) if room.state() == RoomState::Joined => Some(room),
+            _ => {
+                tracing::warn!(
+                    "Not joined to notify room {notify_room}, skipping \
+                     notification"
+                );
+                None
+            }
+        },
+        Err(err) => {
+            tracing::warn!("Invalid notify_room {notify_room}: {err}");
+            None
+        }
+    }
+}
+
+/// Create the sync store directory up front so a permission problem
+/// surfaces as "Can't create store directory <path>: <OS error>"
+/// instead of a panic from deep inside the store.
+fn ensure_store_dir(path: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path)
+        .with_context(|| format!("Can't create store directory {path}"))
+}
+
+/// Body of a webhook import request.
+#[derive(serde::Deserialize)]
+struct WebhookImport {
+    image: String,
+    tag: String,
+}
+
+/// Act on one raw webhook request: authenticate it, parse the image and
+/// tag and queue the same import job the chat command would, with its
+/// output going to the notify room. Returns the HTTP status line and a
+/// JSON body.
+async fn handle_webhook_request(
+    request: &str,
+    token: &str,
+    client: &Client,
+    state: &BotState,
+) -> (&'static str, String) {
+    if !request.starts_with("POST ") {
+        return (
+            "405 Method Not Allowed",
+            r#"{"error":"only POST is supported"}"#.to_string(),
+        );
+    }
+    let Some((head, body)) = request.split_once("\r\n\r\n") else {
+        return (
+            "400 Bad Request",
+            r#"{"error":"malformed request"}"#.to_string(),
+        );
+    };
+    let expected = format!("Bearer {token}");
+    let authorized = head.lines().any(|line| match line.split_once(':') {
+        Some((name, value)) => {
+            name.eq_ignore_ascii_case("authorization")
+                && value.trim() == expected
+        }
+        None => false,
+    });
+    if !authorized {
+        return (
+            "401 Unauthorized",
+            r#"{"error":"missing or wrong token"}"#.to_string(),
+        );
+    }
+    let Ok(payload) = serde_json::from_str::<WebhookImport>(body.trim())
+    else {
+        return (
+            "400 Bad Request",
+            r#"{"error":"expected a JSON body with image and tag"}"#
+                .to_string(),
+        );
+    };
+    let config = state.shared_config.read().unwrap().clone();
+    let Some((image, image_config)) =
+        config.registry.resolve_image(&payload.image)
+    else {
+        // json! so quotes or backslashes in the payload cannot break
+        // the body
+        return (
+            "404 Not Found",
+            serde_json::json!({
+                "error": format!(
+                    "image {} is not configured",
+                    payload.image
+                ),
+            })
+            .to_string(),
+        );
+    };
+    if let Some(allowed) = &image_config.allowed_tags {
+        if !allowed
+            .iter()
+            .any(|pattern| glob_match(pattern, &payload.tag))
+        {
+            return (
+                "403 Forbidden",
+                serde_json::json!({
+                    "error": format!(
+                        "tag {} is not permitted for {image}",
+                        payload.tag
+                    ),
+                })
+                .to_string(),
+            );
+        }
+    }
+    // results have to land somewhere visible; without a notify room the
+    // import would run blind
+    let Some(room) = notify_room(client, &config) else {
+        return (
+            "503 Service Unavailable",
+            r#"{"error":"no notify_room configured"}"#.to_string(),
+        );
+    };
+    let Some(sender) = client.user_id() else {
+        return (
+            "503 Service Unavailable",
+            r#"{"error":"not logged in"}"#.to_string(),
+        );
+    };
+    let job = format!("{image}:{}", payload.tag);
+    if !state.in_flight.lock().unwrap().insert(job.clone()) {
+        return (
+            "409 Conflict",
+            serde_json::json!({
+                "error": format!("import of {job} already in progress"),
+            })
+            .to_string(),
+        );
+    }
+    let id = state.next_job_id.fetch_add(1, Ordering::SeqCst) + 1;
+    state.jobs.lock().unwrap().insert(
+        id,
+        JobInfo {
+            job: job.clone(),
+            sender: sender.to_owned(),
+            status: JobStatus::Queued,
+            event_id: None,
+            abort: None,
+        },
+    );
+    let import = ImportJob {
+        id,
+        image: image.to_string(),
+        thread_root: None,
+        tag: payload.tag.clone(),
+        dest_tag: payload.tag,
+        platform: None,
+        current_digest: None,
+    };
+    let handle = tokio::spawn(run_import_job(
+        import,
+        None,
+        room,
+        config,
+        state.clone(),
+        sender.to_owned(),
+    ));
+    if let Some(info) = state.jobs.lock().unwrap().get_mut(&id) {
+        info.abort = Some(handle.abort_handle());
+    }
+    ("202 Accepted", format!(r#"{{"job":{id}}}"#))
+}
+
+/// Read one HTTP request from the socket: headers until the blank
+/// line, then exactly `Content-Length` bytes of body, so a client that
+/// flushes headers and body separately (or sends a large manifest)
+/// still gets parsed. None on disconnects, malformed framing or
+/// requests over 1 MiB.
+async fn read_http_request(
+    socket: &mut tokio::net::TcpStream,
+) -> Option<String> {
+    const MAX_REQUEST: usize = 1024 * 1024;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        if let Some(position) =
+            buf.windows(4).position(|window| window == b"\r\n\r\n")
+        {
+            break position + 4;
+        }
+        if buf.len() > MAX_REQUEST {
+            return None;
+        }
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => buf.extend_from_slice(&chunk[..read]),
+        }
+    };
+    let content_length = String::from_utf8_lossy(&buf[..header_end])
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.eq_ignore_ascii_case("content-length") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+    if content_length > MAX_REQUEST {
+        return None;
+    }
+    while buf.len() < header_end + content_length {
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => buf.extend_from_slice(&chunk[..read]),
+        }
+    }
+    buf.truncate(header_end + content_length);
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Serve the import webhook on `addr` until the process exits.
+/// Hand-rolled HTTP like the metrics endpoint: enough for a CI curl
+/// call, not a general web server.
+async fn serve_webhook(
+    addr: String,
+    token: String,
+    client: Client,
+    state: BotState,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    tracing::info!("Serving import webhook on {addr}");
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let client = client.clone();
+        let state = state.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let Some(request) = read_http_request(&mut socket).await
+            else {
+                return;
+            };
+            let (status, body) =
+                handle_webhook_request(&request, &token, &client, &state)
+                    .await;
+            let response = format!(
+                "HTTP/1.1 {status}\r\n\
+                 Content-Type: application/json\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Sync settings honoring `matrix.lazy_load_members`: with the toggle
+/// on, a lazy-loading filter keeps the homeserver from sending full
+/// member lists for every room.
+fn sync_settings(config: &Config) -> SyncSettings {
+    let settings = SyncSettings::default();
+    if config.matrix.lazy_load_members {
+        settings.filter(FilterDefinition::with_lazy_loading().into())
+    } else {
+        settings
+    }
+}
+
+/// Establish a session and run the sync loop until shutdown. Returns
+/// true when the `restart` command asked for the session to be torn
+/// down and re-established, false on a regular shutdown.
+async fn login_and_sync(
+    config: Config,
+    config_paths: Vec<String>,
+) -> anyhow::Result<bool> {
+    ensure_store_dir("./store/")?;
+    let mut builder = Client::builder()
+        .homeserver_url(&config.matrix.homeserver)
+        .sqlite_store("./store/", None);
+    match (
+        &config.matrix.client_cert_path,
+        &config.matrix.client_key_path,
+    ) {
+        (Some(cert_path), Some(key_path)) => {
+            // the native-tls backend wants certificate and PKCS#8 key
+            // separately; an identity that fails to parse (or a key
+            // that does not match the certificate) aborts startup with
+            // a clear error
+            let cert = std::fs::read(cert_path).with_context(|| {
+                format!("Could not read client certificate {cert_path}")
+            })?;
+            let key = std::fs::read(key_path).with_context(|| {
+                format!("Could not read client key {key_path}")
+            })?;
+            let identity =
+                matrix_sdk::reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                    .context("Invalid TLS client certificate or key")?;
+            let http_client = matrix_sdk::reqwest::Client::builder()
+                .identity(identity)
+                .build()
+                .context("Could not build HTTP client with certificate")?;
+            builder = builder.http_client(http_client);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!(
+            "matrix.client_cert_path and matrix.client_key_path must be \
+             set together"
+        ),
+    }
+    if config.matrix.encryption {
+        // the crypto state lives in the same sqlite store; replies in
+        // encrypted rooms go to all devices, verified or not
+        builder = builder.with_encryption_settings(EncryptionSettings {
+            auto_enable_cross_signing: true,
+            auto_enable_backups: false,
+            backup_download_strategy: BackupDownloadStrategy::OneShot,
+        });
+    }
+    let client = builder.build().await?;
+
+    if let Some(access_token) = &config.matrix.access_token {
+        let user_id = UserId::parse(&config.matrix.username).context(
+            "matrix.username must be a full MXID when using access_token",
+        )?;
+        let device_id = config
+            .matrix
+            .device_id
+            .clone()
+            .unwrap_or_else(|| "OTCBOT".to_string());
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id,
+                    device_id: device_id.into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: access_token.clone(),
+                    refresh_token: None,
+                },
+            })
+            .await
+            .context("Failed to restore session from access token")?;
+    } else if let Some(password) = &config.matrix.password {
+        // mirror the retry-with-backoff pattern used for room joins: the
+        // homeserver is often briefly unreachable during coordinated
+        // restarts
+        let mut delay = 2;
+        let mut attempt = 0;
+        loop {
+            match client
+                .matrix_auth()
+                .login_username(&config.matrix.username, password)
+                .initial_device_display_name(
+                    config.matrix.device_display_name(),
+                )
+                .await
+            {
+                Ok(_) => break,
+                Err(err) => {
+                    attempt += 1;
+                    if attempt >= config.matrix.max_login_retries() {
+                        return Err(err).context(format!(
+                            "Login failed after {attempt} attempts"
+                        ));
+                    }
+                    tracing::warn!(
+                        "Login attempt {attempt} failed ({err:?}), retrying \
+                         in {delay}s"
+                    );
+                    sleep(Duration::from_secs(delay)).await;
+                    delay = (delay * 2).min(300);
+                }
+            }
+        }
+    } else {
+        anyhow::bail!(
+            "Either matrix.password or matrix.access_token must be set"
+        );
+    }
+
+    tracing::info!("Logged in as {}", config.matrix.username);
+
+    // An initial sync to set up state so that we do not respond to old
+    // messages.
+    let response = client.sync_once(sync_settings(&config)).await?;
+
+    join_configured_rooms(&client, &config);
+
+    if let Some(room) = notify_room(&client, &config) {
+        let content = RoomMessageEventContent::text_plain(format!(
+            "otcbot started, version {}, watching {} images",
+            env!("CARGO_PKG_VERSION"),
+            config.registry.images.len()
+        ));
+        send_message(&room, content).await;
+    }
+
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
+    let state = BotState {
+        started: Instant::now(),
+        last_sync: Arc::new(Mutex::new(None)),
+        command_times: Arc::new(Mutex::new(HashMap::new())),
+        party_times: Arc::new(Mutex::new(HashMap::new())),
+        in_flight: Arc::new(Mutex::new(HashSet::new())),
+        import_slots: Arc::new(Semaphore::new(
+            config.registry.max_concurrent_imports(),
+        )),
+        jobs: Arc::new(Mutex::new(BTreeMap::new())),
+        next_job_id: Arc::new(AtomicU64::new(0)),
+        metrics: Arc::new(Metrics::default()),
+        audit_lock: Arc::new(Mutex::new(())),
+        config_paths: config_paths.clone(),
+        shared_config: shared_config.clone(),
+        restart: Arc::new(Notify::new()),
+    };
+    // handles of the tasks tied to this session; they are aborted on
+    // teardown so a restart does not leave the old session's servers
+    // and schedule running alongside the new ones
+    let mut background: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    if let Some(metrics_addr) = &config.metrics_addr {
+        let addr = metrics_addr.clone();
+        let metrics = state.metrics.clone();
+        background.push(tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr, metrics).await {
+                tracing::error!("Metrics server failed: {err:?}");
+            }
+        }));
+    }
+    if let Some(webhook) = &config.webhook {
+        let addr = webhook.addr.clone();
+        let token = webhook.token.clone();
+        let client = client.clone();
+        let state = state.clone();
+        background.push(tokio::spawn(async move {
+            if let Err(err) =
+                serve_webhook(addr, token, client, state).await
+            {
+                tracing::error!("Webhook server failed: {err:?}");
+            }
+        }));
+    }
+
+    client.add_event_handler_context(shared_config.clone());
+    client.add_event_handler_context(state.clone());
+    client.add_event_handler(on_stripped_state_member);
+    client.add_event_handler(on_room_message);
+
+    if config.schedule.is_some() {
+        let client = client.clone();
+        let state = state.clone();
+        background.push(tokio::spawn(async move {
+            loop {
+                // the interval is re-read each round so a config reload
+                // can change the cadence or stop the schedule entirely
+                let interval = match &state
+                    .shared_config
+                    .read()
+                    .unwrap()
+                    .schedule
+                {
+                    Some(schedule) => schedule.interval_minutes.max(1),
+                    None => break,
+                };
+                sleep(Duration::from_secs(interval * 60)).await;
+                run_scheduled_sync(&client, &state).await;
+            }
+        }));
+    }
+
+    let settings = sync_settings(&config).token(response.next_batch);
+    let last_sync = state.last_sync.clone();
+    // consecutive sync failures; nonzero means we are reconnecting
+    let sync_failures = Arc::new(AtomicU64::new(0));
+    let sync_client = client.clone();
+    let sync_config = shared_config.clone();
+    // the sync future borrows its own clone of the (Arc-backed) client
+    // so the original handle can still be dropped after the loop
+    let syncer = client.clone();
+    let sync = syncer.sync_with_result_callback(settings, move |result| {
+        let last_sync = last_sync.clone();
+        let failures = sync_failures.clone();
+        let client = sync_client.clone();
+        let config = sync_config.clone();
+        async move {
+            match result {
+                Ok(_) => {
+                    *last_sync.lock().unwrap() = Some(SystemTime::now());
+                    let failed = failures.swap(0, Ordering::Relaxed);
+                    if failed > 0 {
+                        tracing::info!(
+                            "Sync re-established after {failed} failed \
+                             attempts"
+                        );
+                        let current = config.read().unwrap().clone();
+                        if let Some(room) = notify_room(&client, &current) {
+                            send_message(
+                                &room,
+                                RoomMessageEventContent::text_plain(
+                                    "otcbot reconnected to the homeserver",
+                                ),
+                            )
+                            .await;
+                        }
+                    }
+                }
+                Err(err) => {
+                    if !is_recoverable_sync_error(&err) {
+                        tracing::error!(
+                            "Sync failed with a fatal error: {err:?}"
+                        );
+                        return Err(err);
+                    }
+                    let attempt =
+                        failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    let delay = 2u64
+                        .saturating_pow(attempt.min(8) as u32)
+                        .min(300);
+                    tracing::warn!(
+                        "Sync failed (attempt {attempt}), reconnecting \
+                         in {delay}s: {err:?}"
+                    );
+                    sleep(Duration::from_secs(delay)).await;
+                }
+            }
+            Ok(LoopCtrl::Continue)
+        }
+    });
+    tokio::pin!(sync);
+
+    let mut sighup =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let restart = state.restart.clone();
+    let mut restart_requested = false;
+    let mut sync_error = None;
+    loop {
+        tokio::select! {
+            result = &mut sync => {
+                sync_error = result.context("Sync stopped").err();
+                break;
+            }
+            _ = restart.notified() => {
+                tracing::info!(
+                    "Restart requested, re-establishing the session"
+                );
+                restart_requested = true;
+                break;
+            }
+            _ = sighup.recv() => {
+                // same as `registry reload`: loading may block on
+                // secret commands, so it runs off the runtime
+                let paths = config_paths.clone();
+                let loaded = tokio::task::spawn_blocking(move || {
+                    Config::from_config_files(&paths)
+                })
+                .await
+                .unwrap_or_else(|err| {
+                    Err(anyhow::anyhow!("config reload panicked: {err}"))
+                });
+                match loaded {
+                    Ok(new_config) => {
+                        *shared_config.write().unwrap() = new_config;
+                        tracing::info!(
+                            "Reloaded config from {}",
+                            config_paths.join(", ")
+                        );
+                    }
+                    Err(err) => tracing::error!(
+                        "Config reload failed, keeping old config: {err:#}"
+                    ),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("Shutdown received");
+                let current = shared_config.read().unwrap().clone();
+                if let Some(room) = notify_room(&client, &current) {
+                    send_message(
+                        &room,
+                        RoomMessageEventContent::text_plain(
+                            "otcbot shutting down",
+                        ),
+                    )
+                    .await;
+                }
+                break;
+            }
+        }
+    }
+
+    for task in background {
+        task.abort();
+    }
+
+    // dropping the client closes the sqlite store and persists the sync
+    // token, so a restart does not re-process old messages
+    drop(client);
+
+    if let Some(err) = sync_error {
+        return Err(err);
+    }
+    Ok(restart_requested)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // LOG_FORMAT=json emits JSON lines for log pipelines; the
+    // human-readable format stays the default. An env var rather than a
+    // config field so logging works before (and during) config loading.
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+        _ => tracing_subscriber::fmt::init(),
+    }
+
+    let cli = Command::new("otcbot")
+        .about("OTC Bot")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .action(ArgAction::Append)
+                .default_value("config.yaml")
+                .help(
+                    "Path to a configuration file; may be given several \
+                     times, later files override earlier ones",
+                ),
+        )
+        .get_matches();
+    let config_paths: Vec<String> = cli
+        .get_many::<String>("config")
+        .unwrap()
+        .cloned()
+        .collect();
+
+    loop {
+        // loaded fresh on every round so a restart after rotating
+        // credentials picks up the new ones
+        let config = match Config::from_config_files(&config_paths) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!(
+                    "Failed to load {}: {err:#}",
+                    config_paths.join(", ")
+                );
+                std::process::exit(1);
+            }
+        };
+        if !login_and_sync(config, config_paths.clone()).await? {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_words_tolerates_extra_whitespace() {
+        assert_eq!(
+            command_words("  !otcbot   registry  import  ", "!otcbot"),
+            Some(vec!["!otcbot", "registry", "import"])
+        );
+    }
+
+    #[test]
+    fn command_words_tolerates_tabs() {
+        assert_eq!(
+            command_words("!otcbot\tregistry\tlist", "!otcbot"),
+            Some(vec!["!otcbot", "registry", "list"])
+        );
+    }
+
+    #[test]
+    fn command_words_is_case_insensitive() {
+        assert_eq!(
+            command_words("!OTCBOT party", "!otcbot"),
+            Some(vec!["!OTCBOT", "party"])
+        );
+    }
+
+    #[test]
+    fn command_words_ignores_other_messages() {
+        assert_eq!(command_words("hello there", "!otcbot"), None);
+        assert_eq!(command_words("", "!otcbot"), None);
+    }
+
+    #[test]
+    fn registry_without_subcommand_shows_registry_help() {
+        let words = vec!["!otcbot", "registry"];
+        let err = otcbot_cmd("!otcbot")
+            .try_get_matches_from(words.clone())
+            .unwrap_err();
+        let reply = command_error_reply("!otcbot", &words, &err);
+        assert!(reply.starts_with("```\n"));
+        assert!(reply.ends_with("```"));
+        assert!(reply.contains("import"));
+        assert!(reply.contains("delete"));
+        assert!(!reply.contains("party"));
+    }
+
+    fn test_config() -> Config {
+        serde_yaml::from_str(
+            "matrix:\n\
+             \x20 homeserver: \"https://matrix.example.com\"\n\
+             \x20 username: \"otcbot\"\n\
+             \x20 admins: [\"@admin:example.com\"]\n\
+             registry:\n\
+             \x20 images: {}\n",
+        )
+        .unwrap()
+    }
+
+    fn dispatch(body: &str, sender: &str, config: &Config) -> Dispatch {
+        let sender = UserId::parse(sender).unwrap();
+        dispatch_command(body, &sender, "!room:example.com", config)
+    }
+
+    #[test]
+    fn dispatch_replies_to_gm() {
+        let config = test_config();
+        assert!(matches!(
+            dispatch("gm", "@user:example.com", &config),
+            Dispatch::Reply(text) if text == "gm to you too"
+        ));
+    }
+
+    #[test]
+    fn gm_template_produces_a_greeting() {
+        let mut config = test_config();
+        config.greetings = Some(crate::config::Greetings {
+            gm: Some("Good morning, {name}!".to_string()),
+        });
+        assert!(matches!(
+            dispatch("gm", "@user:example.com", &config),
+            Dispatch::Greet(template)
+                if template == "Good morning, {name}!"
+        ));
+        assert_eq!(
+            render_greeting("Hi {name} ({mxid})", "Alice", "@a:x"),
+            "Hi Alice (@a:x)"
+        );
+    }
+
+    #[test]
+    fn dispatch_ignores_unaddressed_messages() {
+        let config = test_config();
+        assert!(matches!(
+            dispatch("hello there", "@user:example.com", &config),
+            Dispatch::Ignore
+        ));
+    }
+
+    #[test]
+    fn dispatch_runs_party_for_everyone() {
+        let config = test_config();
+        match dispatch("!otcbot party", "@user:example.com", &config) {
+            Dispatch::Run(matches) => {
+                assert_eq!(matches.subcommand_name(), Some("party"));
+            }
+            other => panic!("expected Run, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn dispatch_denies_registry_for_non_admins() {
+        let config = test_config();
+        assert!(matches!(
+            dispatch("!otcbot registry list", "@user:example.com", &config),
+            Dispatch::Deny(_)
+        ));
+        assert!(matches!(
+            dispatch("!otcbot registry list", "@admin:example.com", &config),
+            Dispatch::Run(_)
+        ));
+    }
+
+    #[test]
+    fn dispatch_reports_unknown_commands_as_usage() {
+        let config = test_config();
+        assert!(matches!(
+            dispatch("!otcbot frobnicate", "@user:example.com", &config),
+            Dispatch::Usage(_)
+        ));
+    }
+
+    #[test]
+    fn typoed_commands_get_a_suggestion() {
+        let config = test_config();
+        let Dispatch::Usage(reply) =
+            dispatch("!otcbot regstry list", "@admin:example.com", &config)
+        else {
+            panic!("expected a usage reply");
+        };
+        assert!(reply.contains("Did you mean `registry`?"));
+        let Dispatch::Usage(reply) = dispatch(
+            "!otcbot registry improt nginx 1.0",
+            "@admin:example.com",
+            &config,
+        ) else {
+            panic!("expected a usage reply");
+        };
+        assert!(reply.contains("Did you mean `import`?"));
+        // nothing close: fall back to the full overview
+        let Dispatch::Usage(reply) =
+            dispatch("!otcbot frobnicate", "@user:example.com", &config)
+        else {
+            panic!("expected a usage reply");
+        };
+        assert!(reply.contains("**General**"));
+    }
+
+    #[test]
+    fn echo_preserves_inner_spacing() {
+        let config = test_config();
+        match dispatch("!otcbot echo a   *b*", "@admin:example.com", &config)
+        {
+            Dispatch::Echo(text) => assert_eq!(text, "a   *b*"),
+            other => panic!("expected Echo, got {other:?}"),
+        }
+        assert!(matches!(
+            dispatch("!otcbot echo hi", "@user:example.com", &config),
+            Dispatch::Deny(_)
+        ));
+        assert!(matches!(
+            dispatch("!otcbot echo", "@admin:example.com", &config),
+            Dispatch::Reply(_)
+        ));
+    }
+
+    #[test]
+    fn dispatch_answers_help_and_bare_prefix() {
+        let config = test_config();
+        assert!(matches!(
+            dispatch("!otcbot help", "@user:example.com", &config),
+            Dispatch::Help(_)
+        ));
+        assert!(matches!(
+            dispatch("!otcbot", "@user:example.com", &config),
+            Dispatch::Help(_)
+        ));
+    }
+
+    #[test]
+    fn help_overview_groups_commands() {
+        let overview = help_overview("!otcbot");
+        assert!(overview.contains("**General**"));
+        assert!(overview.contains("**Registry**"));
+        assert!(overview.contains("`!otcbot party`"));
+        assert!(overview.contains("`!otcbot registry import`"));
+        // registry itself is a group header, not a listed command
+        assert!(!overview.contains("— Container registry operations"));
+    }
+
+    #[test]
+    fn copy_progress_counts_blobs_and_bytes() {
+        let log = "Getting image source signatures\n\
+                   Copying blob sha256:aaa 10.0MiB / 40.0MiB\n\
+                   Copying blob sha256:bbb 5.0MiB / 10.0MiB\n\
+                   Copying blob sha256:bbb done\n";
+        assert_eq!(
+            parse_copy_progress(log).unwrap(),
+            "Copying: 1/2 blobs, 20.0MiB/50.0MiB"
+        );
+        assert_eq!(parse_copy_progress("no blobs here\n"), None);
+    }
+
+    #[test]
+    fn digests_are_recognized() {
+        assert!(is_digest(&format!("sha256:{}", "a".repeat(64))));
+        assert!(!is_digest("sha256:short"));
+        assert!(!is_digest(&format!("sha512:{}", "a".repeat(64))));
+        assert!(!is_digest("1.25"));
+    }
+
+    #[test]
+    fn sizes_parse_and_render() {
+        assert_eq!(parse_size("512B"), Some(512.0));
+        assert_eq!(parse_size("1.5KiB"), Some(1536.0));
+        assert_eq!(parse_size("2MB"), Some(2_000_000.0));
+        assert_eq!(parse_size("garbage"), None);
+        assert_eq!(format_size(1536.0), "1.5KiB");
+        assert_eq!(format_size(100.0), "100B");
+    }
+
+    #[test]
+    fn copy_summary_sums_blob_sizes() {
+        let log = "Copying blob aaa 10.0MiB / 20.0MiB\n\
+                   Copying blob aaa done\n\
+                   Copying blob bbb 1.0MiB / 4.0MiB\n";
+        let bytes = copied_size_from_log(log).unwrap();
+        assert_eq!(format_size(bytes), "24.0MiB");
+        assert_eq!(copied_size_from_log("no blobs here"), None);
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(422)), "7m 02s");
+    }
+
+    #[test]
+    fn audit_history_filters_and_orders() {
+        let path = std::env::temp_dir().join("otcbot-history.jsonl");
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"timestamp\":1,\"sender\":\"@a:x\",\"room\":\"!r:x\",\
+                 \"image\":\"nginx\",\"tag\":\"1.0\",\"success\":true}\n",
+                "not json\n",
+                "{\"timestamp\":2,\"sender\":\"@b:x\",\"room\":\"!r:x\",\
+                 \"image\":\"redis\",\"tag\":\"7\",\"success\":false}\n",
+            ),
+        )
+        .unwrap();
+        let all = read_audit_history(path.to_str().unwrap(), None, 10);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].image, "redis"); // newest first
+        let filtered =
+            read_audit_history(path.to_str().unwrap(), Some("nginx"), 10);
+        assert_eq!(filtered.len(), 1);
+        assert!(filtered[0].success);
+        assert!(read_audit_history("/nonexistent", None, 10).is_empty());
+    }
+
+    #[test]
+    fn globs_match_tags() {
+        assert!(glob_match("nightly-*", "nightly-2024-01-01"));
+        assert!(glob_match("v1.2.?", "v1.2.3"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("v1.2.?", "v1.2.34"));
+        assert!(!glob_match("nightly-*", "release-1"));
+    }
+
+    #[test]
+    fn redaction_applies_every_pattern() {
+        let redactions = compile_redactions(&[
+            "Bearer \\S+".to_string(),
+            "token=[a-z0-9]+".to_string(),
+            "not a (regex".to_string(),
+        ]);
+        // the invalid pattern is skipped, not fatal
+        assert_eq!(redactions.len(), 2);
+        assert_eq!(
+            redact_log(
+                "auth: Bearer abc123\nGET /v2/?token=deadbeef\n",
+                &redactions,
+            ),
+            "auth: ***\nGET /v2/?***\n"
+        );
+    }
+
+    #[test]
+    fn redaction_handles_overlapping_matches() {
+        let redactions = compile_redactions(&[
+            "secret-[0-9]+".to_string(),
+            "[0-9]+-suffix".to_string(),
+        ]);
+        // the first pattern consumes the digits, the second still
+        // matches what remains of its own occurrence
+        assert_eq!(
+            redact_log("secret-123-suffix and 9-suffix", &redactions),
+            "***-suffix and ***"
+        );
+    }
+
+    #[test]
+    fn truncation_keeps_the_tail() {
+        let log: String =
+            (1..=100).map(|i| format!("line {i}\n")).collect();
+        let truncated = truncate_log(&log, 10);
+        assert!(truncated.starts_with("...(truncated, 90 lines omitted)"));
+        assert!(truncated.ends_with("line 100"));
+        assert!(!truncated.contains("line 90\n"));
+        assert_eq!(truncate_log("short\n", 10), "short\n");
+    }
+
+    #[test]
+    fn unwritable_store_dir_is_a_contextual_error() {
+        // a plain file as parent fails create_dir_all even when the
+        // tests run as root, unlike a read-only directory
+        let file = std::env::temp_dir().join("otcbot-store-parent");
+        std::fs::write(&file, b"not a directory").unwrap();
+        let path = format!("{}/store", file.display());
+        let err = ensure_store_dir(&path).unwrap_err();
+        assert!(format!("{err:#}")
+            .contains(&format!("Can't create store directory {path}")));
+    }
+
+    #[test]
+    fn own_messages_are_ignored() {
+        let bot = UserId::parse("@otcbot:example.com").unwrap();
+        let user = UserId::parse("@alice:example.com").unwrap();
+        assert!(is_own_message(&bot, Some(&bot)));
+        assert!(!is_own_message(&user, Some(&bot)));
+        assert!(!is_own_message(&user, None));
+    }
+}
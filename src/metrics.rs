@@ -0,0 +1,207 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Histogram buckets for skopeo copy durations, in seconds.
+const DURATION_BUCKETS: [f64; 7] =
+    [30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 3600.0];
+
+/// Counters exposed in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<HashMap<String, u64>>,
+    /// Per-command (succeeded, failed) counts, for the `stats` command.
+    command_results: Mutex<HashMap<String, (u64, u64)>>,
+    imports_success: AtomicU64,
+    imports_failure: AtomicU64,
+    copy_durations: Mutex<Vec<f64>>,
+}
+
+impl Metrics {
+    /// Count one invocation of the given top-level command.
+    pub fn record_command(&self, command: &str) {
+        *self
+            .commands
+            .lock()
+            .unwrap()
+            .entry(command.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Count the outcome of one finished command.
+    pub fn record_command_result(&self, command: &str, success: bool) {
+        let mut results = self.command_results.lock().unwrap();
+        let entry = results.entry(command.to_string()).or_insert((0, 0));
+        if success {
+            entry.0 += 1;
+        } else {
+            entry.1 += 1;
+        }
+    }
+
+    /// Snapshot of (command, runs, succeeded, failed) sorted by command
+    /// name. Runs can exceed succeeded plus failed: not every run
+    /// reports an outcome.
+    pub fn command_stats(&self) -> Vec<(String, u64, u64, u64)> {
+        let commands = self.commands.lock().unwrap();
+        let results = self.command_results.lock().unwrap();
+        let mut stats: Vec<(String, u64, u64, u64)> = commands
+            .iter()
+            .map(|(name, runs)| {
+                let (succeeded, failed) =
+                    results.get(name).copied().unwrap_or((0, 0));
+                (name.clone(), *runs, succeeded, failed)
+            })
+            .collect();
+        stats.sort();
+        stats
+    }
+
+    /// Count one finished import together with its copy duration.
+    pub fn record_import(&self, success: bool, duration_secs: f64) {
+        if success {
+            self.imports_success.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.imports_failure.fetch_add(1, Ordering::Relaxed);
+        }
+        self.copy_durations.lock().unwrap().push(duration_secs);
+    }
+
+    /// Render all metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP otcbot_commands_total Commands received per command.\n\
+             # TYPE otcbot_commands_total counter\n",
+        );
+        let commands = self.commands.lock().unwrap();
+        let mut names: Vec<&String> = commands.keys().collect();
+        names.sort();
+        for name in names {
+            out.push_str(&format!(
+                "otcbot_commands_total{{command=\"{}\"}} {}\n",
+                name, commands[name]
+            ));
+        }
+        drop(commands);
+
+        out.push_str(
+            "# HELP otcbot_imports_total Finished imports per result.\n\
+             # TYPE otcbot_imports_total counter\n",
+        );
+        out.push_str(&format!(
+            "otcbot_imports_total{{result=\"success\"}} {}\n",
+            self.imports_success.load(Ordering::Relaxed)
+        ));
+        out.push_str(&format!(
+            "otcbot_imports_total{{result=\"failure\"}} {}\n",
+            self.imports_failure.load(Ordering::Relaxed)
+        ));
+
+        out.push_str(
+            "# HELP otcbot_copy_duration_seconds Duration of skopeo \
+             copies.\n\
+             # TYPE otcbot_copy_duration_seconds histogram\n",
+        );
+        let durations = self.copy_durations.lock().unwrap();
+        for bucket in DURATION_BUCKETS {
+            let count =
+                durations.iter().filter(|d| **d <= bucket).count();
+            out.push_str(&format!(
+                "otcbot_copy_duration_seconds_bucket{{le=\"{bucket}\"}} \
+                 {count}\n"
+            ));
+        }
+        out.push_str(&format!(
+            "otcbot_copy_duration_seconds_bucket{{le=\"+Inf\"}} {}\n",
+            durations.len()
+        ));
+        out.push_str(&format!(
+            "otcbot_copy_duration_seconds_sum {}\n",
+            durations.iter().sum::<f64>()
+        ));
+        out.push_str(&format!(
+            "otcbot_copy_duration_seconds_count {}\n",
+            durations.len()
+        ));
+        out
+    }
+}
+
+/// Serve the metrics on `addr` until the process exits. Every request
+/// gets the full exposition; the request itself is not inspected.
+pub async fn serve(addr: String, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    tracing::info!("Serving metrics on {addr}");
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_includes_counters() {
+        let metrics = Metrics::default();
+        metrics.record_command("registry");
+        metrics.record_command("registry");
+        metrics.record_import(true, 42.0);
+        metrics.record_import(false, 700.0);
+        let out = metrics.render();
+        assert!(out
+            .contains("otcbot_commands_total{command=\"registry\"} 2"));
+        assert!(out.contains("otcbot_imports_total{result=\"success\"} 1"));
+        assert!(out.contains("otcbot_imports_total{result=\"failure\"} 1"));
+        assert!(out.contains("otcbot_copy_duration_seconds_count 2"));
+    }
+
+    #[test]
+    fn command_stats_count_outcomes() {
+        let metrics = Metrics::default();
+        metrics.record_command("ping");
+        metrics.record_command("registry");
+        metrics.record_command("registry");
+        metrics.record_command_result("registry", true);
+        metrics.record_command_result("registry", false);
+        assert_eq!(
+            metrics.command_stats(),
+            vec![
+                ("ping".to_string(), 1, 0, 0),
+                ("registry".to_string(), 2, 1, 1),
+            ]
+        );
+    }
+}